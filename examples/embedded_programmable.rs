@@ -20,8 +20,7 @@ extern crate rusqlite;
 
 #[cfg(feature = "d-sqlite")]
 use migrant_lib::{
-    Config, ConnConfig, Direction, EmbeddedMigration, FileMigration, FnMigration, Migrator,
-    Settings,
+    Config, Direction, EmbeddedMigration, FileMigration, Migrator, Settings, TxFnMigration,
 };
 #[cfg(feature = "d-sqlite")]
 use rusqlite::types::ToSql;
@@ -34,25 +33,21 @@ mod migrations {
     pub struct AddUserData;
 
     impl AddUserData {
-        pub fn up(config: ConnConfig) -> Result<(), Box<dyn std::error::Error>> {
-            let db_path = config.database_path()?;
-            let conn = rusqlite::Connection::open(&db_path)?;
+        pub fn up(tx: &rusqlite::Transaction) -> Result<(), Box<dyn std::error::Error>> {
             let people = ["james", "lauren", "bean"];
             for (i, name) in people.iter().enumerate() {
                 let id = i as u32 + 1;
-                conn.execute(
+                tx.execute(
                     "insert into users (id, name) values (?1, ?2);",
-                    &[&id as &dyn ToSql, name],
+                    [&id as &dyn ToSql, name],
                 )?;
             }
             Ok(())
         }
-        pub fn down(config: ConnConfig) -> Result<(), Box<dyn std::error::Error>> {
-            let db_path = config.database_path()?;
-            let conn = rusqlite::Connection::open(&db_path)?;
+        pub fn down(tx: &rusqlite::Transaction) -> Result<(), Box<dyn std::error::Error>> {
             let people = ["james", "lauren", "bean"];
             for name in &people {
-                conn.execute("delete from users where name = ?1", &[name])?;
+                tx.execute("delete from users where name = ?1", [name])?;
             }
             Ok(())
         }
@@ -78,7 +73,7 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
                 "../migrations/embedded/create_users_table/down.sql"
             ))
             .boxed(),
-        FnMigration::with_tag("add-user-data")
+        TxFnMigration::with_tag("add-user-data")
             .up(migrations::AddUserData::up)
             .down(migrations::AddUserData::down)
             .boxed(),
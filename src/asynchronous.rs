@@ -0,0 +1,438 @@
+/*!
+Async migrations
+
+Enabled by the `async` feature, along with `async-postgres` (`tokio-postgres`),
+`async-mysql` (`mysql_async`) or `async-sqlite` (`rusqlite` run on tokio's blocking threads)
+for the built-in async drivers. Must be run within a tokio runtime.
+
+The async methods mirror their blocking counterparts: `Config::setup_async`, `Config::reload_async`,
+`Migrator::apply_async` and `Migrator::execute_async`, with `_with_async` variants
+taking an existing `AsyncDriver` connection.
+
+```rust,no_run
+# extern crate migrant_lib;
+# use migrant_lib::{Config, Migrator};
+# async fn run() -> Result<(), Box<dyn std::error::Error>> {
+let config = Config::from_settings_file("Migrant.toml")?;
+config.setup_async().await?;
+let config = config.reload_async().await?;
+Migrator::with_config(&config)
+    .all(true)
+    .swallow_completion(true)
+    .apply_async()
+    .await?;
+# Ok(())
+# }
+# fn main() {}
+```
+
+The returned futures are `Send`, so they can be `tokio::spawn`ed on a multi-threaded runtime.
+Migrations that aren't defined by SQL statements are run with `Migratable::apply_up_async`
+and `Migratable::apply_down_async`, which default to running the blocking `apply_up`/`apply_down`
+on tokio's blocking threads.
+*/
+use std::io;
+use std::time::Instant;
+
+use crate::drivers::{self, AsyncDriver};
+use crate::errors::*;
+use crate::run;
+use crate::{Config, DbKind, Direction, Migratable, Migrator, Plan, PlanStep};
+
+impl Config {
+    /// Open a connection with the built-in async driver for the configured database type
+    async fn connect_async(&self) -> Result<Box<dyn AsyncDriver>> {
+        Ok(match self.database_type() {
+            DbKind::Sqlite => Box::new(
+                <drivers::async_sqlite::AsyncSqliteConn as AsyncDriver>::connect(self).await?,
+            ),
+            DbKind::Postgres => {
+                Box::new(<drivers::async_pg::Conn as AsyncDriver>::connect(self).await?)
+            }
+            DbKind::MySql => {
+                Box::new(<drivers::async_mysql::Conn as AsyncDriver>::connect(self).await?)
+            }
+        })
+    }
+
+    /// Async version of `Config::setup`
+    pub async fn setup_async(&self) -> Result<bool> {
+        if let DbKind::Sqlite = self.database_type() {
+            drivers::sqlite::create_file_if_missing(&self.database_path()?)?;
+        }
+        let mut conn = self.connect_async().await?;
+        self.setup_on_async(conn.as_mut()).await
+    }
+
+    /// Async version of `Config::setup_with`
+    pub async fn setup_with_async<D: AsyncDriver>(&self, conn: &mut D) -> Result<bool> {
+        self.check_database_type(conn.database_type())?;
+        self.setup_on_async(conn).await
+    }
+
+    async fn setup_on_async(&self, conn: &mut dyn AsyncDriver) -> Result<bool> {
+        debug!("\n ** Setting up migrations table");
+        let table = self.migration_table()?;
+        if conn.migration_setup(&table).await? {
+            debug!("    - migrations table missing");
+            debug!("    - `{}` table created ✓", table);
+            Ok(true)
+        } else {
+            debug!("    - `{}` table already exists ✓", table);
+            if conn.migration_table_upgrade(&table).await? {
+                debug!("    - `{}` table upgraded ✓", table);
+            }
+            Ok(false)
+        }
+    }
+
+    /// Async version of `Config::reload`
+    pub async fn reload_async(&self) -> Result<Config> {
+        let config = self.reload_settings()?;
+        let mut conn = config.connect_async().await?;
+        config.reload_on_async(conn.as_mut()).await
+    }
+
+    /// Async version of `Config::reload_with`
+    pub async fn reload_with_async<D: AsyncDriver>(&self, conn: &mut D) -> Result<Config> {
+        let config = self.reload_settings()?;
+        config.check_database_type(conn.database_type())?;
+        config.reload_on_async(conn).await
+    }
+
+    async fn reload_on_async(&self, conn: &mut dyn AsyncDriver) -> Result<Config> {
+        let applied = self.load_applied_async(conn).await?;
+        let recorded = conn.select_checksums(&self.migration_table()?).await?;
+        self.with_loaded(applied, recorded)
+    }
+
+    /// Load the applied migrations from the database migration table
+    async fn load_applied_async(&self, conn: &mut dyn AsyncDriver) -> Result<Vec<String>> {
+        let table = self.migration_table()?;
+        if !conn.migration_table_exists(&table).await? {
+            bail_fmt!(
+                ErrorKind::Migration,
+                "`{}` table is missing, maybe try re-setting-up? -> `setup`",
+                table
+            )
+        }
+        self.check_applied(conn.select_migrations(&table).await?)
+    }
+}
+
+impl Migrator {
+    /// Async version of `Migrator::apply`
+    pub async fn apply_async(&self) -> Result<()> {
+        if self.dry_run {
+            return self.swallow(self.dry_run_to(&mut io::stdout()));
+        }
+        let res = match self.config.connect_async().await {
+            Ok(mut conn) => self.apply_on_async(conn.as_mut()).await,
+            Err(e) => Err(e),
+        };
+        self.swallow(res)
+    }
+
+    /// Async version of `Migrator::apply_with`
+    ///
+    /// ```rust,no_run
+    /// # extern crate migrant_lib;
+    /// # use migrant_lib::{Config, EmbeddedMigration, Migrator, Settings};
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// # #[cfg(feature = "async-sqlite")]
+    /// # {
+    /// use migrant_lib::AsyncSqliteConn;
+    ///
+    /// let mut conn = AsyncSqliteConn::new(rusqlite::Connection::open_in_memory()?);
+    /// let settings = Settings::configure_sqlite()
+    ///     .database_path(":memory:")?
+    ///     .build()?;
+    /// let mut config = Config::with_settings(&settings);
+    /// config.setup_with_async(&mut conn).await?;
+    /// config.use_migrations(&[
+    ///     EmbeddedMigration::with_tag("create-users-table")
+    ///         .up("create table users (id integer primary key, name text);")
+    ///         .down("drop table users;")
+    ///         .boxed(),
+    /// ])?;
+    /// let config = config.reload_with_async(&mut conn).await?;
+    /// Migrator::with_config(&config)
+    ///     .all(true)
+    ///     .apply_with_async(&mut conn)
+    ///     .await?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    pub async fn apply_with_async<D: AsyncDriver>(&self, conn: &mut D) -> Result<()> {
+        if self.dry_run {
+            return self.swallow(self.dry_run_to(&mut io::stdout()));
+        }
+        let res = match self.config.check_database_type(conn.database_type()) {
+            Ok(_) => self.apply_on_async(conn).await,
+            Err(e) => Err(e),
+        };
+        self.swallow(res)
+    }
+
+    async fn apply_on_async(&self, conn: &mut dyn AsyncDriver) -> Result<()> {
+        self.acquire_lock_async(conn).await?;
+        let res = async {
+            let config = self.current_config_async(&mut *conn).await?;
+            let migrations = config.available_migrations()?;
+            let plan = self.plan_with(&config, &migrations)?;
+            self.execute_plan_async(&config, &mut *conn, &plan, &migrations)
+                .await
+        }
+        .await;
+        self.release_lock_async(conn, res).await
+    }
+
+    /// Async version of `Migrator::execute`
+    pub async fn execute_async(&self, plan: &Plan) -> Result<()> {
+        if self.dry_run {
+            return self.swallow(self.dry_run_plan(plan));
+        }
+        let res = match self.config.connect_async().await {
+            Ok(mut conn) => self.execute_on_async(plan, conn.as_mut()).await,
+            Err(e) => Err(e),
+        };
+        self.swallow(res)
+    }
+
+    /// Async version of `Migrator::execute_with`
+    pub async fn execute_with_async<D: AsyncDriver>(
+        &self,
+        plan: &Plan,
+        conn: &mut D,
+    ) -> Result<()> {
+        if self.dry_run {
+            return self.swallow(self.dry_run_plan(plan));
+        }
+        let res = match self.config.check_database_type(conn.database_type()) {
+            Ok(_) => self.execute_on_async(plan, conn).await,
+            Err(e) => Err(e),
+        };
+        self.swallow(res)
+    }
+
+    async fn execute_on_async(&self, plan: &Plan, conn: &mut dyn AsyncDriver) -> Result<()> {
+        self.acquire_lock_async(conn).await?;
+        let res = async {
+            let config = self.current_config_async(&mut *conn).await?;
+            Self::check_plan(&config, plan)?;
+            let migrations = config.available_migrations()?;
+            self.execute_plan_async(&config, &mut *conn, plan, &migrations)
+                .await
+        }
+        .await;
+        self.release_lock_async(conn, res).await
+    }
+
+    /// Acquire the migration lock, if enabled
+    async fn acquire_lock_async(&self, conn: &mut dyn AsyncDriver) -> Result<()> {
+        if self.lock {
+            let table = self.config.migration_table()?;
+            conn.acquire_lock(&table, self.lock_timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// Return the `Config` to run with, re-loading applied migrations and
+    /// checksums if the lock is held
+    async fn current_config_async(&self, conn: &mut dyn AsyncDriver) -> Result<Config> {
+        if self.lock {
+            self.config.reload_on_async(conn).await
+        } else {
+            Ok(self.config.clone())
+        }
+    }
+
+    /// Release the migration lock, if enabled, returning the result of the locked run
+    async fn release_lock_async(&self, conn: &mut dyn AsyncDriver, res: Result<()>) -> Result<()> {
+        if !self.lock {
+            return res;
+        }
+        let released = match self.config.migration_table() {
+            Ok(table) => conn.release_lock(&table).await,
+            Err(e) => Err(e),
+        };
+        res.and(released)
+    }
+
+    async fn execute_plan_async(
+        &self,
+        config: &Config,
+        conn: &mut dyn AsyncDriver,
+        plan: &Plan,
+        migrations: &[Box<dyn Migratable>],
+    ) -> Result<()> {
+        Self::check_not_empty(plan)?;
+        for step in plan.steps() {
+            let migration = Self::step_migration(migrations, step)?;
+            self.run_step_async(config, &mut *conn, step, migration)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Run a single planned migration, respecting `fake` and `force`
+    async fn run_step_async(
+        &self,
+        config: &Config,
+        conn: &mut dyn AsyncDriver,
+        step: &PlanStep,
+        migration: &dyn Migratable,
+    ) -> Result<()> {
+        let record = if self.start_step(step) {
+            // errors aren't `Send`, keep only the message across the awaits below
+            let res = Self::run_migration_async(config, &mut *conn, &step.direction, migration)
+                .await
+                .map_err(|e| e.to_string());
+            self.finish_step(res)?
+        } else {
+            true
+        };
+        if record {
+            let op = run::record_op(config, migration, &step.direction, 0)?;
+            Self::run_ops_async(config, conn, vec![op])
+                .await
+                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Apply the migration in the specified direction and update the migration table.
+    /// See `Migrator::run_migration`.
+    async fn run_migration_async(
+        config: &Config,
+        conn: &mut dyn AsyncDriver,
+        direction: &Direction,
+        migration: &dyn Migratable,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let db_kind = config.database_type();
+        let stmt = migration.statements(&db_kind, direction)?;
+        let ops = match stmt {
+            Some(stmt) => run::statement_ops(config, migration, direction, stmt)?,
+            None => {
+                let start = Instant::now();
+                match *direction {
+                    Direction::Up => migration.apply_up_async(db_kind, config).await?,
+                    Direction::Down => migration.apply_down_async(db_kind, config).await?,
+                };
+                let duration_ms = start.elapsed().as_millis() as i64;
+                vec![run::record_op(config, migration, direction, duration_ms)?]
+            }
+        };
+        Self::run_ops_async(config, conn, ops).await?;
+        Ok(())
+    }
+
+    /// Run the database operations of a migration on `conn`. See `Migrator::run_ops`.
+    async fn run_ops_async(
+        config: &Config,
+        conn: &mut dyn AsyncDriver,
+        ops: Vec<run::Op>,
+    ) -> Result<()> {
+        let table = config.migration_table()?;
+        for op in ops {
+            match op {
+                run::Op::Batch(stmt) => conn.execute_batch(&stmt).await?,
+                run::Op::Apply {
+                    stmt,
+                    record,
+                    direction,
+                    transactional,
+                } => {
+                    conn.apply_migration(&table, &stmt, &record, &direction, transactional)
+                        .await?
+                }
+                run::Op::Insert(record, duration_ms) => {
+                    conn.insert_migration_tag(&table, &record, duration_ms)
+                        .await?
+                }
+                run::Op::Remove(tag) => conn.remove_migration_tag(&table, &tag).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Settings;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn futures_are_send() {
+        let settings = Settings::configure_sqlite()
+            .database_path(":memory:")
+            .unwrap()
+            .build()
+            .unwrap();
+        let config = Config::with_settings(&settings);
+        let migrator = Migrator::with_config(&config);
+        let plan = Plan {
+            direction: Direction::Up,
+            steps: vec![],
+        };
+        assert_send(&config.setup_async());
+        assert_send(&config.reload_async());
+        assert_send(&migrator.apply_async());
+        assert_send(&migrator.execute_async(&plan));
+    }
+
+    #[cfg(feature = "async-sqlite")]
+    #[test]
+    fn blocking_migrations_run_async() {
+        use crate::drivers::async_sqlite::AsyncSqliteConn;
+        use crate::{ConnConfig, FnMigration};
+
+        fn create(conn: ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            let conn = rusqlite::Connection::open(conn.database_path()?)?;
+            conn.execute_batch("create table created_by_fn (id int);")?;
+            Ok(())
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "migrant_lib_test_{}_blocking_async.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let settings = Settings::configure_sqlite()
+            .database_path(&path)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut config = Config::with_settings(&settings);
+        config
+            .use_migrations(&[FnMigration::with_tag("create")
+                .up(create)
+                .down(crate::migration::noop)
+                .boxed()])
+            .unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let mut conn = AsyncSqliteConn::new(rusqlite::Connection::open(&path)?);
+                config.setup_with_async(&mut conn).await?;
+                let config = config.reload_with_async(&mut conn).await?;
+                let mut migrator = Migrator::with_config(&config);
+                migrator.show_output(false);
+                assert_send(&migrator.apply_with_async(&mut conn));
+                migrator.apply_with_async(&mut conn).await
+            })
+            .unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("select * from created_by_fn;")
+            .expect("Assert blocking migration ran");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,5 +1,8 @@
+/*!
+Database migration connection
+
+*/
 use crate::errors::*;
-///! Database migration connection
 use crate::{Config, DbKind};
 
 #[allow(dead_code)]
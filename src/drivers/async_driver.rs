@@ -0,0 +1,92 @@
+use super::*;
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+
+use crate::{Config, DbKind, Direction};
+
+/// An async database backend that migrations are run on, the async counterpart of `Driver`
+///
+/// Implemented for `tokio_postgres::Client` (`async-postgres`), `mysql_async::Conn` (`async-mysql`)
+/// and `AsyncSqliteConn` (`async-sqlite`), which runs `rusqlite` on tokio's blocking threads.
+/// Other backends can implement `AsyncDriver` and be passed to `Migrator::apply_with_async`.
+#[async_trait]
+pub trait AsyncDriver: Send {
+    /// Open a new connection using the settings of `config`
+    async fn connect(config: &Config) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// The database type this driver connects to, if it's one of the built-in types.
+    /// Used to confirm connections passed to `Migrator::apply_with_async` match the `Config`.
+    /// Defaults to `None`
+    fn database_type(&self) -> Option<DbKind> {
+        None
+    }
+
+    /// Check the migration table exists
+    async fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool>;
+
+    /// Create the migration table if it doesn't exist, returning true if it was created
+    async fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool>;
+
+    /// Add any columns missing from a migration table created by an older version,
+    /// returning true if it was altered. Defaults to doing nothing
+    async fn migration_table_upgrade(&mut self, _table: &MigrationTable) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Select all applied migration tags, in the order they were applied
+    async fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>>;
+
+    /// Select the tags and checksums of applied migrations recorded with a checksum.
+    /// Defaults to nothing, which disables detecting modified migrations
+    async fn select_checksums(&mut self, _table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        Ok(vec![])
+    }
+
+    /// Insert a record into the migration table
+    async fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()>;
+
+    /// Remove a tag from the migration table
+    async fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()>;
+
+    /// Execute a batch of SQL statements
+    async fn execute_batch(&mut self, stmt: &str) -> Result<()>;
+
+    /// Run migration statements and insert or remove `record` from the migration table.
+    /// When `transactional`, both should be committed together.
+    ///
+    /// Defaults to `execute_batch` followed by the migration table update, without a transaction
+    async fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        _transactional: bool,
+    ) -> Result<()> {
+        let start = Instant::now();
+        self.execute_batch(stmt).await?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => self.insert_migration_tag(table, record, duration_ms).await,
+            Direction::Down => self.remove_migration_tag(table, &record.tag).await,
+        }
+    }
+
+    /// Acquire a lock so only one process runs migrations against `table` at a time,
+    /// waiting up to `timeout`. Defaults to not locking
+    async fn acquire_lock(&mut self, _table: &MigrationTable, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Release the lock taken by `acquire_lock`
+    async fn release_lock(&mut self, _table: &MigrationTable) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use super::*;
+use crate::{Config, DbKind, Direction};
+/// Async MySQL database functions using mysql_async
+use async_trait::async_trait;
+use std::time::Duration;
+
+#[cfg(feature = "async-mysql")]
+use mysql_async::{prelude::*, Opts, TxOpts};
+#[cfg(feature = "async-mysql")]
+use std::time::Instant;
+
+#[cfg(not(feature = "async-mysql"))]
+#[allow(unused_variables)]
+mod m {
+    use super::*;
+    pub struct Conn;
+    pub async fn connect(conn_str: &str) -> Result<Conn> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn remove_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        tag: &str,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn acquire_lock(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        timeout: Duration,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+    pub async fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-mysql feature");
+    }
+}
+
+#[cfg(feature = "async-mysql")]
+mod m {
+    use super::*;
+    pub use mysql_async::Conn;
+
+    /// Open a connection to the database
+    pub async fn connect(conn_str: &str) -> Result<Conn> {
+        let opts = Opts::from_url(conn_str)
+            .chain_err(|| "Error parsing mysql connection string".to_string())?;
+        Ok(Conn::new(opts).await.chain_err(|| "Connection Error")?)
+    }
+
+    /// Check the migration table exists
+    pub async fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let rows: Vec<u32> = conn.query(sql::mysql_migration_table_exists(table)).await?;
+        assert_eq!(
+            rows.len(),
+            1,
+            "Migration table check: Expected 1 returned row"
+        );
+        Ok(rows[0] == 1)
+    }
+
+    /// Create the migration table
+    pub async fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        if !migration_table_exists(conn, table).await? {
+            conn.query_drop(sql::mysql_create_table(table))
+                .await
+                .chain_err(|| "Error setting up migration table")?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Select all migrations from the migration table
+    pub async fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        Ok(conn.query(sql::get_migrations(table)).await?)
+    }
+
+    /// Select the tags and checksums of migrations recorded with a checksum.
+    /// Returns nothing if the table hasn't been upgraded to store checksums.
+    pub async fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        let columns: Vec<String> = conn.query(sql::mysql_migration_columns(table)).await?;
+        if !columns
+            .iter()
+            .any(|col| col.eq_ignore_ascii_case("checksum"))
+        {
+            return Ok(vec![]);
+        }
+        Ok(conn.query(sql::get_checksums(table)).await?)
+    }
+
+    /// Add any columns missing from a migration table
+    /// created by an older version, returning true if the table was altered
+    pub async fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let existing: Vec<String> = conn.query(sql::mysql_migration_columns(table)).await?;
+        let mut upgraded = false;
+        for &(name, kind) in sql::MYSQL_COLUMNS {
+            if !existing.iter().any(|col| col.eq_ignore_ascii_case(name)) {
+                conn.query_drop(sql::add_column(table, name, kind))
+                    .await
+                    .chain_err(|| "Error upgrading migration table")?;
+                upgraded = true;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    async fn insert_record<C: Queryable>(
+        conn: &mut C,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        conn.exec_drop(
+            sql::mysql_insert_migration(table),
+            (
+                &record.tag,
+                duration_ms,
+                &record.applied_by,
+                &record.checksum,
+                record.repeatable,
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Insert migration tag into the migration table
+    pub async fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_record(conn, table, record, duration_ms).await
+    }
+
+    /// Delete migration tag from the migration table
+    pub async fn remove_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        tag: &str,
+    ) -> Result<()> {
+        conn.exec_drop(sql::mysql_delete_migration(table), (tag,))
+            .await?;
+        Ok(())
+    }
+
+    async fn run_and_tag<C: Queryable>(
+        conn: &mut C,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        conn.query_drop(stmt)
+            .await
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => insert_record(conn, table, record, duration_ms).await?,
+            Direction::Down => {
+                conn.exec_drop(sql::mysql_delete_migration(table), (&record.tag,))
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    /// Apply migration statements and update the migration table
+    /// on a single connection. When `transactional`, both are committed together.
+    ///
+    /// *Note:* MySQL implicitly commits most DDL statements, so only the
+    /// remaining statements can be rolled back on failure.
+    pub async fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        if transactional {
+            let mut tx = conn.start_transaction(TxOpts::default()).await?;
+            run_and_tag(&mut tx, table, stmt, record, direction).await?;
+            tx.commit().await?;
+        } else {
+            run_and_tag(conn, table, stmt, record, direction).await?;
+        }
+        Ok(())
+    }
+
+    /// Execute a batch of statements
+    pub async fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        conn.query_drop(stmt)
+            .await
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(())
+    }
+
+    /// Name of the `GET_LOCK` lock for `table`. Lock names are server-wide,
+    /// so unqualified tables are scoped to the current database
+    async fn lock_name(conn: &mut Conn, table: &MigrationTable) -> Result<String> {
+        let database: Option<String> = match table.schema {
+            Some(ref schema) => Some(schema.clone()),
+            None => conn
+                .query_first::<Option<String>, _>("select database()")
+                .await?
+                .flatten(),
+        };
+        let scoped = MigrationTable {
+            schema: database,
+            name: table.name.clone(),
+        };
+        Ok(format!("migrant_{:016x}", lock_key(&scoped)))
+    }
+
+    /// Acquire the migration lock with `GET_LOCK`, waiting up to `timeout`
+    pub async fn acquire_lock(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        timeout: Duration,
+    ) -> Result<()> {
+        let name = lock_name(conn, table).await?;
+        let acquired: Option<Option<i32>> = conn
+            .exec_first("select get_lock(?, ?)", (&name, timeout.as_secs()))
+            .await?;
+        if acquired.flatten() != Some(1) {
+            bail!(lock_timeout_error(table, timeout))
+        }
+        Ok(())
+    }
+
+    /// Release the migration lock acquired on this connection
+    pub async fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        let name = lock_name(conn, table).await?;
+        conn.exec_drop("select release_lock(?)", (&name,)).await?;
+        Ok(())
+    }
+}
+
+pub use self::m::*;
+
+#[async_trait]
+impl AsyncDriver for Conn {
+    async fn connect(config: &Config) -> Result<Self> {
+        connect(&config.connect_string()?).await
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::MySql)
+    }
+    async fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_exists(self, table).await
+    }
+    async fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_setup(self, table).await
+    }
+    async fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_upgrade(self, table).await
+    }
+    async fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        select_migrations(self, table).await
+    }
+    async fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        select_checksums(self, table).await
+    }
+    async fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_migration_tag(self, table, record, duration_ms).await
+    }
+    async fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        remove_migration_tag(self, table, tag).await
+    }
+    async fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        execute_batch(self, stmt).await
+    }
+    async fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        apply_migration(self, table, stmt, record, direction, transactional).await
+    }
+    async fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        acquire_lock(self, table, timeout).await
+    }
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        release_lock(self, table).await
+    }
+}
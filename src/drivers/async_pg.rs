@@ -0,0 +1,380 @@
+use super::*;
+use crate::{Config, DbKind, Direction};
+/// Async postgres database functions using tokio-postgres
+use async_trait::async_trait;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "async-postgres")]
+use std::future::Future;
+#[cfg(feature = "async-postgres")]
+use std::time::Instant;
+#[cfg(feature = "async-postgres")]
+use tokio_postgres::{GenericClient, NoTls};
+
+#[cfg(not(feature = "async-postgres"))]
+#[allow(unused_variables)]
+mod m {
+    use super::*;
+    pub struct Conn;
+    pub async fn connect(cert: Option<&Path>, conn_str: &str) -> Result<Conn> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn remove_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        tag: &str,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn acquire_lock(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        timeout: Duration,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+    pub async fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        unimplemented!("migrant_lib: must enable async-postgres feature");
+    }
+}
+
+#[cfg(feature = "async-postgres")]
+mod m {
+    use super::*;
+    pub use tokio_postgres::Client as Conn;
+
+    fn make_connector(cert: &Path) -> Result<postgres_native_tls::MakeTlsConnector> {
+        let cert = std::fs::read(cert)
+            .map_err(|e| format_err!(ErrorKind::Migration, "postgres cert file error {}", e))?;
+        let cert = native_tls::Certificate::from_pem(&cert)
+            .map_err(|e| format_err!(ErrorKind::Migration, "postgres cert load error {}", e))?;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(cert)
+            .build()
+            .map_err(|e| {
+                format_err!(ErrorKind::Migration, "postgres tls-connection error {}", e)
+            })?;
+        Ok(postgres_native_tls::MakeTlsConnector::new(connector))
+    }
+
+    /// Drive a connection on a spawned task until its client is dropped
+    fn spawn_connection<F>(connection: F)
+    where
+        F: Future<Output = std::result::Result<(), tokio_postgres::Error>> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("postgres connection error: {}", e);
+            }
+        });
+    }
+
+    /// Open a connection to the database
+    pub async fn connect(cert: Option<&Path>, conn_str: &str) -> Result<Conn> {
+        let client = match cert {
+            None => {
+                let (client, connection) = tokio_postgres::connect(conn_str, NoTls)
+                    .await
+                    .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+                spawn_connection(connection);
+                client
+            }
+            Some(cert) => {
+                let (client, connection) = tokio_postgres::connect(conn_str, make_connector(cert)?)
+                    .await
+                    .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+                spawn_connection(connection);
+                client
+            }
+        };
+        Ok(client)
+    }
+
+    /// Check the migration table exists
+    pub async fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let row = conn
+            .query_one(&sql::pg_migration_table_exists(table), &[])
+            .await
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(row.get(0))
+    }
+
+    /// Create the migration table
+    pub async fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        if !migration_table_exists(conn, table).await? {
+            if let Some(ref schema) = table.schema {
+                conn.batch_execute(&sql::pg_create_schema(schema))
+                    .await
+                    .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+            }
+            conn.batch_execute(&sql::pg_create_table(table))
+                .await
+                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Select all migrations from the migration table
+    pub async fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        let rows = conn.query(&sql::get_migrations(table), &[]).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn migration_columns(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        Ok(conn
+            .query(&sql::pg_migration_columns(table), &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    /// Select the tags and checksums of migrations recorded with a checksum.
+    /// Returns nothing if the table hasn't been upgraded to store checksums.
+    pub async fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        if !migration_columns(conn, table)
+            .await?
+            .iter()
+            .any(|col| col == "checksum")
+        {
+            return Ok(vec![]);
+        }
+        let rows = conn.query(&sql::get_checksums(table), &[]).await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Add any columns missing from a migration table
+    /// created by an older version, returning true if the table was altered
+    pub async fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let existing = migration_columns(conn, table).await?;
+        let mut upgraded = false;
+        for &(name, kind) in sql::PG_COLUMNS {
+            if !existing.iter().any(|col| col == name) {
+                conn.batch_execute(&sql::add_column(table, name, kind))
+                    .await?;
+                upgraded = true;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    async fn insert_record<C: GenericClient>(
+        conn: &C,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        conn.execute(
+            &sql::insert_migration(table),
+            &[
+                &record.tag,
+                &duration_ms,
+                &record.applied_by,
+                &record.checksum,
+                &record.repeatable,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Insert migration tag into the migration table
+    pub async fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_record(conn, table, record, duration_ms).await
+    }
+
+    /// Delete migration tag from the migration table
+    pub async fn remove_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        tag: &str,
+    ) -> Result<()> {
+        conn.execute(&sql::delete_migration(table), &[&tag]).await?;
+        Ok(())
+    }
+
+    async fn run_and_tag<C: GenericClient>(
+        conn: &C,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        conn.batch_execute(stmt)
+            .await
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => insert_record(conn, table, record, duration_ms).await?,
+            Direction::Down => {
+                conn.execute(&sql::delete_migration(table), &[&record.tag])
+                    .await?;
+            }
+        };
+        Ok(())
+    }
+
+    /// Apply migration statements and update the migration table
+    /// on a single connection. When `transactional`, both are committed together.
+    pub async fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        if transactional {
+            let tx = conn.transaction().await?;
+            run_and_tag(&tx, table, stmt, record, direction).await?;
+            tx.commit().await?;
+        } else {
+            run_and_tag(conn, table, stmt, record, direction).await?;
+        }
+        Ok(())
+    }
+
+    /// Execute a batch of statements
+    pub async fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        conn.batch_execute(stmt)
+            .await
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(())
+    }
+
+    /// Acquire the migration lock as a session-level advisory lock, waiting up to `timeout`
+    pub async fn acquire_lock(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        timeout: Duration,
+    ) -> Result<()> {
+        let key = lock_key(table);
+        let start = Instant::now();
+        loop {
+            let row = conn
+                .query_one("select pg_try_advisory_lock($1)", &[&key])
+                .await?;
+            if row.get::<_, bool>(0) {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                bail!(lock_timeout_error(table, timeout))
+            }
+            tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Release the migration lock acquired on this connection
+    pub async fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        conn.execute("select pg_advisory_unlock($1)", &[&lock_key(table)])
+            .await?;
+        Ok(())
+    }
+}
+
+pub use self::m::*;
+
+#[async_trait]
+impl AsyncDriver for Conn {
+    async fn connect(config: &Config) -> Result<Self> {
+        connect(config.ssl_cert_file().as_deref(), &config.connect_string()?).await
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::Postgres)
+    }
+    async fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_exists(self, table).await
+    }
+    async fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_setup(self, table).await
+    }
+    async fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_upgrade(self, table).await
+    }
+    async fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        select_migrations(self, table).await
+    }
+    async fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        select_checksums(self, table).await
+    }
+    async fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_migration_tag(self, table, record, duration_ms).await
+    }
+    async fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        remove_migration_tag(self, table, tag).await
+    }
+    async fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        execute_batch(self, stmt).await
+    }
+    async fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        apply_migration(self, table, stmt, record, direction, transactional).await
+    }
+    async fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        acquire_lock(self, table, timeout).await
+    }
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        release_lock(self, table).await
+    }
+}
@@ -0,0 +1,141 @@
+use super::*;
+use crate::{Config, DbKind, Direction};
+use async_trait::async_trait;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A sqlite connection whose queries are run on tokio's blocking threads
+///
+/// Used as the async sqlite driver since sqlite has no async client. Wraps a `rusqlite::Connection`.
+#[derive(Clone)]
+pub struct AsyncSqliteConn {
+    conn: Arc<Mutex<sqlite::Conn>>,
+}
+impl AsyncSqliteConn {
+    /// Wrap an open connection
+    pub fn new(conn: sqlite::Conn) -> Self {
+        Self {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Open a connection to the database file at `db_path`
+    pub async fn open(db_path: &Path) -> Result<Self> {
+        let db_path = db_path.to_owned();
+        Ok(Self::new(
+            blocking(move || sqlite::connect(&db_path)).await?,
+        ))
+    }
+
+    /// Run `f` with the connection on a blocking thread
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut sqlite::Conn) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        blocking(move || {
+            let mut conn = conn.lock().map_err(|_| {
+                format_err!(ErrorKind::Migration, "sqlite connection lock poisoned")
+            })?;
+            f(&mut conn)
+        })
+        .await
+    }
+}
+
+/// Run `f` on a blocking thread
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?
+}
+
+#[async_trait]
+impl AsyncDriver for AsyncSqliteConn {
+    async fn connect(config: &Config) -> Result<Self> {
+        Self::open(&config.database_path()?).await
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::Sqlite)
+    }
+    async fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::migration_table_exists(conn, &table))
+            .await
+    }
+    async fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::migration_setup(conn, &table))
+            .await
+    }
+    async fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::migration_table_upgrade(conn, &table))
+            .await
+    }
+    async fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::select_migrations(conn, &table))
+            .await
+    }
+    async fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::select_checksums(conn, &table))
+            .await
+    }
+    async fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        let (table, record) = (table.clone(), record.clone());
+        self.run(move |conn| sqlite::insert_migration_tag(conn, &table, &record, duration_ms))
+            .await
+    }
+    async fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        let (table, tag) = (table.clone(), tag.to_owned());
+        self.run(move |conn| sqlite::remove_migration_tag(conn, &table, &tag))
+            .await
+    }
+    async fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        let stmt = stmt.to_owned();
+        self.run(move |conn| sqlite::execute_batch(conn, &stmt))
+            .await
+    }
+    async fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        let (table, stmt, record, direction) = (
+            table.clone(),
+            stmt.to_owned(),
+            record.clone(),
+            direction.clone(),
+        );
+        self.run(move |conn| {
+            sqlite::apply_migration(conn, &table, &stmt, &record, &direction, transactional)
+        })
+        .await
+    }
+    async fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::acquire_lock(conn, &table, timeout))
+            .await
+    }
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        let table = table.clone();
+        self.run(move |conn| sqlite::release_lock(conn, &table))
+            .await
+    }
+}
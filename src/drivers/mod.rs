@@ -1,18 +1,543 @@
 use super::errors::*;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
 
+use crate::{Config, DbKind, Direction};
+
+#[allow(dead_code)]
 mod sql {
-    pub static CREATE_TABLE: &str = "create table __migrant_migrations(tag text unique);";
-    pub static MYSQL_CREATE_TABLE: &str =
-        "create table __migrant_migrations(tag varchar(512) unique);";
+    use super::MigrationTable;
+    use crate::DbKind;
+
+    pub fn create_table(table: &MigrationTable) -> String {
+        format!(
+            "create table {}(tag text unique, \
+             applied_at text, duration_ms integer, applied_by text, checksum text, repeatable boolean);",
+            table
+        )
+    }
+    pub fn pg_create_table(table: &MigrationTable) -> String {
+        format!(
+            "create table {}(tag text unique, \
+             applied_at timestamp with time zone, duration_ms bigint, applied_by text, checksum text, \
+             repeatable boolean);",
+            table
+        )
+    }
+    pub fn mysql_create_table(table: &MigrationTable) -> String {
+        format!(
+            "create table {}(tag varchar(512) unique, \
+             applied_at datetime, duration_ms bigint, applied_by varchar(512), checksum varchar(128), \
+             repeatable boolean);",
+            table
+        )
+    }
+    pub fn pg_create_schema(schema: &str) -> String {
+        format!("create schema if not exists {};", schema)
+    }
+
+    // Columns added to the migration table after the original `tag` column.
+    // Tables created by older versions are upgraded in place during `Config::setup`.
+    pub static COLUMNS: &[(&str, &str)] = &[
+        ("applied_at", "text"),
+        ("duration_ms", "integer"),
+        ("applied_by", "text"),
+        ("checksum", "text"),
+        ("repeatable", "boolean"),
+    ];
+    pub static PG_COLUMNS: &[(&str, &str)] = &[
+        ("applied_at", "timestamp with time zone"),
+        ("duration_ms", "bigint"),
+        ("applied_by", "text"),
+        ("checksum", "text"),
+        ("repeatable", "boolean"),
+    ];
+    pub static MYSQL_COLUMNS: &[(&str, &str)] = &[
+        ("applied_at", "datetime"),
+        ("duration_ms", "bigint"),
+        ("applied_by", "varchar(512)"),
+        ("checksum", "varchar(128)"),
+        ("repeatable", "boolean"),
+    ];
+    pub fn add_column(table: &MigrationTable, name: &str, kind: &str) -> String {
+        format!("alter table {} add column {} {};", table, name, kind)
+    }
+
+    // Repeatable migrations are re-applied when changed, so aren't listed as applied
+    pub fn get_migrations(table: &MigrationTable) -> String {
+        format!(
+            "select tag from {} where repeatable is null or not repeatable;",
+            table
+        )
+    }
+    // Without an explicit order, sqlite may scan the `tag` index and return tags sorted
+    pub fn sqlite_get_migrations(table: &MigrationTable) -> String {
+        format!(
+            "select tag from {} where repeatable is null or not repeatable order by rowid;",
+            table
+        )
+    }
+    pub fn get_checksums(table: &MigrationTable) -> String {
+        format!(
+            "select tag, checksum from {} where checksum is not null;",
+            table
+        )
+    }
+
+    pub fn insert_migration(table: &MigrationTable) -> String {
+        format!(
+            "insert into {} (tag, applied_at, duration_ms, applied_by, checksum, repeatable) \
+             values ($1, current_timestamp, $2, $3, $4, $5)",
+            table
+        )
+    }
+    pub fn mysql_insert_migration(table: &MigrationTable) -> String {
+        format!(
+            "insert into {} (tag, applied_at, duration_ms, applied_by, checksum, repeatable) \
+             values (?, current_timestamp, ?, ?, ?, ?)",
+            table
+        )
+    }
+    pub fn delete_migration(table: &MigrationTable) -> String {
+        format!("delete from {} where tag = $1", table)
+    }
+    pub fn mysql_delete_migration(table: &MigrationTable) -> String {
+        format!("delete from {} where tag = ?", table)
+    }
+
+    /// Quoted string literal. MySQL also treats backslashes as escapes by default
+    pub fn literal(kind: &DbKind, s: &str) -> String {
+        let s = s.replace('\'', "''");
+        match *kind {
+            DbKind::MySql => format!("'{}'", s.replace('\\', "\\\\")),
+            _ => format!("'{}'", s),
+        }
+    }
+    pub fn insert_migration_literal(
+        kind: &DbKind,
+        table: &MigrationTable,
+        tag: &str,
+        applied_by: &str,
+        checksum: Option<&str>,
+        repeatable: bool,
+    ) -> String {
+        format!(
+            "insert into {} (tag, applied_at, duration_ms, applied_by, checksum, repeatable) \
+             values ({}, current_timestamp, null, {}, {}, {});",
+            table,
+            literal(kind, tag),
+            literal(kind, applied_by),
+            checksum
+                .map(|checksum| literal(kind, checksum))
+                .unwrap_or_else(|| "null".to_string()),
+            repeatable
+        )
+    }
+    pub fn delete_migration_literal(kind: &DbKind, table: &MigrationTable, tag: &str) -> String {
+        format!("delete from {} where tag = {};", table, literal(kind, tag))
+    }
+
+    /// Quoted schema name, or the given expression for the default schema
+    fn schema_or(table: &MigrationTable, default: &str) -> String {
+        match table.schema {
+            Some(ref schema) => format!("'{}'", schema),
+            None => default.to_string(),
+        }
+    }
+
+    pub fn sqlite_migration_table_exists(table: &MigrationTable) -> String {
+        let master = match table.schema {
+            Some(ref schema) => format!("{}.sqlite_master", schema),
+            None => "sqlite_master".to_string(),
+        };
+        format!(
+            "select exists(select 1 from {} where type = 'table' and name = '{}');",
+            master, table.name
+        )
+    }
+    pub fn pg_migration_table_exists(table: &MigrationTable) -> String {
+        format!(
+            "select exists(select 1 from pg_tables where schemaname = {} and tablename = '{}');",
+            schema_or(table, "current_schema()"),
+            table.name
+        )
+    }
+    pub fn mysql_migration_table_exists(table: &MigrationTable) -> String {
+        format!(
+            "select exists(select 1 from information_schema.tables \
+             where table_schema = {} and table_name = '{}') as tag;",
+            schema_or(table, "database()"),
+            table.name
+        )
+    }
+
+    pub fn sqlite_migration_columns(table: &MigrationTable) -> String {
+        match table.schema {
+            Some(ref schema) => format!(
+                "select name from pragma_table_info('{}', '{}');",
+                table.name, schema
+            ),
+            None => format!("select name from pragma_table_info('{}');", table.name),
+        }
+    }
+    pub fn pg_migration_columns(table: &MigrationTable) -> String {
+        format!(
+            "select column_name::text from information_schema.columns \
+             where table_schema = {} and table_name = '{}';",
+            schema_or(table, "current_schema()"),
+            table.name
+        )
+    }
+    pub fn mysql_migration_columns(table: &MigrationTable) -> String {
+        format!(
+            "select column_name from information_schema.columns \
+             where table_schema = {} and table_name = '{}';",
+            schema_or(table, "database()"),
+            table.name
+        )
+    }
+}
+
+/// Default name of the table used to track applied migrations
+pub static DEFAULT_MIGRATION_TABLE: &str = "__migrant_migrations";
+
+/// Table used to track applied migrations, optionally qualified with a schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationTable {
+    /// Schema containing the table, the database default when `None`
+    pub schema: Option<String>,
+    /// Table name
+    pub name: String,
+}
+impl MigrationTable {
+    /// Parse a `name` or `schema.name` table name. An empty string is the default table.
+    ///
+    /// Names are interpolated into queries, so each part is restricted to `[a-z_][a-z0-9_]*`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        let valid = |part: &str| {
+            part.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        };
+        let (schema, name) = match s.find('.') {
+            Some(ind) => (Some(&s[..ind]), &s[ind + 1..]),
+            None => (None, s),
+        };
+        if !valid(name) || !schema.map(valid).unwrap_or(true) {
+            bail_fmt!(
+                ErrorKind::Config,
+                "Invalid migration table: `{}`. Expected `name` or `schema.name` \
+                 containing only `[a-z0-9_]`",
+                s
+            )
+        }
+        Ok(Self {
+            schema: schema.map(String::from),
+            name: name.to_string(),
+        })
+    }
+}
+impl Default for MigrationTable {
+    fn default() -> Self {
+        Self {
+            schema: None,
+            name: DEFAULT_MIGRATION_TABLE.to_string(),
+        }
+    }
+}
+impl fmt::Display for MigrationTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.schema {
+            Some(ref schema) => write!(f, "{}.{}", schema, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Interval between attempts to acquire a migration lock
+#[allow(dead_code)]
+static LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A database backend that migrations are run on
+///
+/// Implemented for `rusqlite::Connection`, `postgres::Client` and `mysql::Conn` when the
+/// respective database feature is enabled. Other backends can implement `Driver` and be
+/// registered with `Config::use_driver`, or passed directly to `Migrator::apply_with`.
+pub trait Driver {
+    /// Open a new connection using the settings of `config`
+    fn connect(config: &Config) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// The database type this driver connects to, if it's one of the built-in types.
+    /// Used to confirm connections passed to `Migrator::apply_with` match the `Config`.
+    /// Defaults to `None`
+    fn database_type(&self) -> Option<DbKind> {
+        None
+    }
+
+    /// Check the migration table exists
+    fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool>;
+
+    /// Create the migration table if it doesn't exist, returning true if it was created
+    fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool>;
+
+    /// Add any columns missing from a migration table created by an older version,
+    /// returning true if it was altered. Defaults to doing nothing
+    fn migration_table_upgrade(&mut self, _table: &MigrationTable) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Select all applied migration tags, in the order they were applied
+    fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>>;
+
+    /// Select the tags and checksums of applied migrations recorded with a checksum.
+    /// Defaults to nothing, which disables detecting modified migrations
+    fn select_checksums(&mut self, _table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        Ok(vec![])
+    }
+
+    /// Insert a record into the migration table
+    fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()>;
+
+    /// Remove a tag from the migration table
+    fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()>;
+
+    /// Execute a batch of SQL statements
+    fn execute_batch(&mut self, stmt: &str) -> Result<()>;
 
-    pub static GET_MIGRATIONS: &str = "select tag from __migrant_migrations;";
+    /// Run migration statements and insert or remove `record` from the migration table.
+    /// When `transactional`, both should be committed together.
+    ///
+    /// Defaults to `execute_batch` followed by the migration table update, without a transaction
+    fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        _transactional: bool,
+    ) -> Result<()> {
+        let start = Instant::now();
+        self.execute_batch(stmt)?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => self.insert_migration_tag(table, record, duration_ms),
+            Direction::Down => self.remove_migration_tag(table, &record.tag),
+        }
+    }
 
-    pub static SQLITE_MIGRATION_TABLE_EXISTS: &str = "select exists(select 1 from sqlite_master where type = 'table' and name = '__migrant_migrations');";
-    pub static PG_MIGRATION_TABLE_EXISTS: &str =
-        "select exists(select 1 from pg_tables where tablename = '__migrant_migrations');";
-    pub static MYSQL_MIGRATION_TABLE_EXISTS: &str = "select exists(select 1 from information_schema.tables where table_name='__migrant_migrations') as tag;";
+    /// Acquire a lock so only one process runs migrations against `table` at a time,
+    /// waiting up to `timeout`. Defaults to not locking
+    fn acquire_lock(&mut self, _table: &MigrationTable, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Release the lock taken by `acquire_lock`
+    fn release_lock(&mut self, _table: &MigrationTable) -> Result<()> {
+        Ok(())
+    }
+
+    /// Select rows of text columns. Used to read the migration tables of other
+    /// tools when importing their applied migrations. Defaults to an error
+    fn select_text_rows(&mut self, _stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        bail_fmt!(
+            ErrorKind::Migration,
+            "Selecting rows is not supported by this driver"
+        )
+    }
+
+    /// Return the underlying connection as `Any`. Used by `TxFnMigration`s
+    /// to open a transaction on a built-in connection. Defaults to `None`
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        None
+    }
+}
+
+/// Return the built-in `C` connection underlying `conn`, required by migration `tag`
+#[allow(dead_code)]
+fn downcast_conn<'a, C: 'static>(
+    conn: &'a mut dyn Driver,
+    kind: DbKind,
+    tag: &str,
+) -> Result<&'a mut C> {
+    conn.as_any_mut()
+        .and_then(|conn| conn.downcast_mut::<C>())
+        .ok_or_else(|| {
+            format_err!(
+                ErrorKind::Migration,
+                "Migration `{}` requires a {} connection",
+                tag,
+                kind
+            )
+            .into()
+        })
+}
+
+/// Return a stable 64 bit key identifying the lock for `table`
+#[allow(dead_code)]
+fn lock_key(table: &MigrationTable) -> i64 {
+    let digest = Sha256::digest(format!("migrant:{}", table).as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes)
+}
+
+#[allow(dead_code)]
+fn lock_timeout_error(table: &MigrationTable, timeout: Duration) -> ErrorKind {
+    format_err!(
+        ErrorKind::LockTimeout,
+        "Unable to acquire the migration lock for `{}` within {:?}, \
+         another process may be running migrations",
+        table,
+        timeout
+    )
+}
+
+/// Render the migration table update for `record` with literal values, for use in SQL scripts
+pub(crate) fn render_migration_tag(
+    kind: &DbKind,
+    table: &MigrationTable,
+    record: &MigrationRecord,
+    direction: &Direction,
+) -> String {
+    match *direction {
+        Direction::Up => sql::insert_migration_literal(
+            kind,
+            table,
+            &record.tag,
+            &record.applied_by,
+            record.checksum.as_deref(),
+            record.repeatable,
+        ),
+        Direction::Down => sql::delete_migration_literal(kind, table, &record.tag),
+    }
+}
+
+/// Statement starting a transaction, for use in SQL scripts
+pub(crate) fn render_begin(kind: &DbKind) -> &'static str {
+    match *kind {
+        DbKind::MySql => "start transaction;",
+        _ => "begin;",
+    }
+}
+
+/// Details recorded in the migration table for an applied migration
+#[derive(Debug, Clone)]
+pub struct MigrationRecord {
+    /// Migration tag
+    pub tag: String,
+    /// `user@host` that applied the migration
+    pub applied_by: String,
+    /// Checksum of the applied migration content
+    pub checksum: Option<String>,
+    /// Whether the migration is repeatable, see `Migratable::is_repeatable`
+    pub repeatable: bool,
+}
+impl MigrationRecord {
+    /// Create a new record for the given tag, applied by the current user and host
+    pub fn with_tag(tag: &str) -> Self {
+        Self {
+            tag: tag.to_owned(),
+            applied_by: applied_by(),
+            checksum: None,
+            repeatable: false,
+        }
+    }
+
+    /// Set the checksum of the applied content
+    pub fn checksum(mut self, checksum: Option<String>) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Set whether the migration is repeatable
+    pub fn repeatable(mut self, repeatable: bool) -> Self {
+        self.repeatable = repeatable;
+        self
+    }
+}
+
+/// Return `user@host` for the current process, using `unknown` for missing values
+fn applied_by() -> String {
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".into());
+    let host = env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .or_else(|_| fs::read_to_string("/etc/hostname").map(|s| s.trim().to_string()))
+        .unwrap_or_else(|_| "unknown".into());
+    format!("{}@{}", user, host)
 }
 
 pub mod mysql;
 pub mod pg;
 pub mod sqlite;
+
+#[cfg(feature = "async")]
+mod async_driver;
+#[cfg(feature = "async")]
+pub mod async_mysql;
+#[cfg(feature = "async")]
+pub mod async_pg;
+#[cfg(feature = "async")]
+pub mod async_sqlite;
+#[cfg(feature = "async")]
+pub use self::async_driver::AsyncDriver;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_migration_table() {
+        let table = MigrationTable::parse("migrations").unwrap();
+        assert_eq!(None, table.schema, "Assert unqualified table has no schema");
+        assert_eq!("migrations", table.name, "Assert table name");
+
+        let table = MigrationTable::parse(" app_1.__migrations ").unwrap();
+        assert_eq!(
+            Some("app_1"),
+            table.schema.as_deref(),
+            "Assert qualified table schema"
+        );
+        assert_eq!("__migrations", table.name, "Assert qualified table name");
+        assert_eq!("app_1.__migrations", table.to_string(), "Assert display");
+
+        assert_eq!(
+            MigrationTable::default(),
+            MigrationTable::parse("").unwrap(),
+            "Assert empty name is the default table"
+        );
+
+        for name in &[
+            ".migrations",
+            "app.",
+            ".",
+            "app.schema.migrations",
+            "Migrations",
+            "1migrations",
+            "migrations; drop table users",
+            "app.\"migrations\"",
+        ] {
+            match MigrationTable::parse(name) {
+                Err(ref e) if matches!(*e.kind(), ErrorKind::Config(_)) => (),
+                res => panic!("Expected a config error for {:?}, found: {:?}", name, res),
+            }
+        }
+    }
+}
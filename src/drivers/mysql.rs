@@ -1,32 +1,56 @@
 use super::*;
+use crate::{Config, DbKind, Direction};
 /// MySQL database functions using shell commands and db drivers
 use std;
 use std::path::Path;
 
+#[cfg(feature = "d-mysql")]
 use std::io::Read;
+use std::time::Duration;
+#[cfg(feature = "d-mysql")]
+use std::time::Instant;
 
 #[cfg(feature = "d-mysql")]
-use ::mysql::{prelude::*, Conn, Opts};
+use ::mysql::{prelude::*, Opts, TxOpts};
 
 #[cfg(not(feature = "d-mysql"))]
+#[allow(unused_variables)]
 mod m {
     use super::*;
+    pub struct Conn;
     pub fn can_connect(conn_str: &str) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
-    pub fn migration_table_exists(conn_str: &str) -> Result<bool> {
+    pub fn connect(conn_str: &str) -> Result<Conn> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
-    pub fn migration_setup(conn_str: &str) -> Result<bool> {
+    pub fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
-    pub fn select_migrations(conn_str: &str) -> Result<Vec<String>> {
+    pub fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
-    pub fn insert_migration_tag(conn_str: &str, tag: &str) -> Result<()> {
+    pub fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
-    pub fn remove_migration_tag(conn_str: &str, tag: &str) -> Result<()> {
+    pub fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn remove_migration_tag(conn: &mut Conn, table: &MigrationTable, tag: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
     pub fn run_migration(conn_str: &str, filename: &Path) -> Result<()> {
@@ -35,11 +59,36 @@ mod m {
     pub fn run_migration_str(conn_str: &str, stmt: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-mysql feature");
     }
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn select_text_rows(conn: &mut Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn acquire_lock(conn: &mut Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
+    pub fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-mysql feature");
+    }
 }
 
 #[cfg(feature = "d-mysql")]
 mod m {
     use super::*;
+    use crate::migration::TxFn;
+    pub use ::mysql::Conn;
+
     /// Check connection
     pub fn can_connect(conn_str: &str) -> Result<bool> {
         let conn_opts = Opts::from_url(conn_str)
@@ -53,12 +102,16 @@ mod m {
         Ok(true)
     }
 
-    /// Check `__migrant_migrations` table exists
-    pub fn migration_table_exists(conn_str: &str) -> Result<bool> {
+    /// Open a connection to the database
+    pub fn connect(conn_str: &str) -> Result<Conn> {
         let conn_str = Opts::from_url(conn_str)
             .chain_err(|| "Error parsing mysql connection string".to_string())?;
-        let mut conn = Conn::new(conn_str).chain_err(|| "Connection Error")?;
-        let rows: Vec<u32> = conn.query(sql::MYSQL_MIGRATION_TABLE_EXISTS)?;
+        Ok(Conn::new(conn_str).chain_err(|| "Connection Error")?)
+    }
+
+    /// Check the migration table exists
+    pub fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let rows: Vec<u32> = conn.query(sql::mysql_migration_table_exists(table))?;
         assert_eq!(
             rows.len(),
             1,
@@ -67,42 +120,84 @@ mod m {
         Ok(rows[0] == 1)
     }
 
-    /// Create `__migrant_migrations` table
-    pub fn migration_setup(conn_str: &str) -> Result<bool> {
-        if !migration_table_exists(conn_str)? {
-            let conn_str = Opts::from_url(conn_str)
-                .chain_err(|| "Error parsing mysql connection string".to_string())?;
-            let mut conn = Conn::new(conn_str).chain_err(|| "Connection Error")?;
-            conn.query_drop(sql::MYSQL_CREATE_TABLE)
+    /// Create the migration table
+    pub fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        if !migration_table_exists(conn, table)? {
+            conn.query_drop(sql::mysql_create_table(table))
                 .chain_err(|| "Error setting up migration table")?;
             return Ok(true);
         }
         Ok(false)
     }
 
-    /// Select all migrations from `__migrant_migrations` table
-    pub fn select_migrations(conn_str: &str) -> Result<Vec<String>> {
-        let conn_str = Opts::from_url(conn_str)
-            .chain_err(|| "Error parsing mysql connection string".to_string())?;
-        let mut conn = Conn::new(conn_str).chain_err(|| "Connection Error")?;
-        Ok(conn.query(sql::GET_MIGRATIONS)?)
+    /// Select all migrations from the migration table
+    pub fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        Ok(conn.query(sql::get_migrations(table))?)
     }
 
-    /// Insert migration tag into `__migrant_migrations` table
-    pub fn insert_migration_tag(conn_str: &str, tag: &str) -> Result<()> {
-        let conn_str = Opts::from_url(conn_str)
-            .chain_err(|| "Error parsing mysql connection string".to_string())?;
-        let mut conn = Conn::new(conn_str).chain_err(|| "Connection Error")?;
-        conn.exec_drop("insert into __migrant_migrations (tag) values (?)", (tag,))?;
+    /// Select the tags and checksums of migrations recorded with a checksum.
+    /// Returns nothing if the table hasn't been upgraded to store checksums.
+    pub fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        let columns: Vec<String> = conn.query(sql::mysql_migration_columns(table))?;
+        if !columns
+            .iter()
+            .any(|col| col.eq_ignore_ascii_case("checksum"))
+        {
+            return Ok(vec![]);
+        }
+        Ok(conn.query(sql::get_checksums(table))?)
+    }
+
+    /// Add any columns missing from a migration table
+    /// created by an older version, returning true if the table was altered
+    pub fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let existing: Vec<String> = conn.query(sql::mysql_migration_columns(table))?;
+        let mut upgraded = false;
+        for &(name, kind) in sql::MYSQL_COLUMNS {
+            if !existing.iter().any(|col| col.eq_ignore_ascii_case(name)) {
+                conn.query_drop(sql::add_column(table, name, kind))
+                    .chain_err(|| "Error upgrading migration table")?;
+                upgraded = true;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    fn insert_record<C: Queryable>(
+        conn: &mut C,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        conn.exec_drop(
+            sql::mysql_insert_migration(table),
+            (
+                &record.tag,
+                duration_ms,
+                &record.applied_by,
+                &record.checksum,
+                record.repeatable,
+            ),
+        )?;
         Ok(())
     }
 
-    /// Delete migration tag from `__migrant_migrations` table
-    pub fn remove_migration_tag(conn_str: &str, tag: &str) -> Result<()> {
-        let conn_str = Opts::from_url(conn_str)
-            .chain_err(|| "Error parsing mysql connection string".to_string())?;
-        let mut conn = Conn::new(conn_str).chain_err(|| "Connection Error")?;
-        conn.exec_drop("delete from __migrant_migrations where tag = ?", (tag,))?;
+    /// Insert migration tag into the migration table
+    pub fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_record(conn, table, record, duration_ms)
+    }
+
+    /// Delete migration tag from the migration table
+    pub fn remove_migration_tag(conn: &mut Conn, table: &MigrationTable, tag: &str) -> Result<()> {
+        conn.exec_drop(sql::mysql_delete_migration(table), (tag,))?;
         Ok(())
     }
 
@@ -128,10 +223,200 @@ mod m {
             .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         Ok(())
     }
+
+    fn run_and_tag<C: Queryable>(
+        conn: &mut C,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        conn.query_drop(stmt)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => insert_record(conn, table, record, duration_ms)?,
+            Direction::Down => {
+                conn.exec_drop(sql::mysql_delete_migration(table), (&record.tag,))?
+            }
+        };
+        Ok(())
+    }
+
+    /// Apply migration statements and update the migration table
+    /// on a single connection. When `transactional`, both are committed together.
+    ///
+    /// *Note:* MySQL implicitly commits most DDL statements, so only the
+    /// remaining statements can be rolled back on failure.
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        if transactional {
+            let mut tx = conn.start_transaction(TxOpts::default())?;
+            run_and_tag(&mut tx, table, stmt, record, direction)?;
+            tx.commit()?;
+        } else {
+            run_and_tag(conn, table, stmt, record, direction)?;
+        }
+        Ok(())
+    }
+
+    impl<F> TxFn<::mysql::Transaction<'static>> for F
+    where
+        F: 'static
+            + Send
+            + Sync
+            + Fn(&mut ::mysql::Transaction<'_>) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    {
+        fn run_tx(
+            &self,
+            conn: &mut dyn Driver,
+            table: &MigrationTable,
+            record: &MigrationRecord,
+            direction: &Direction,
+        ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            let conn: &mut Conn = downcast_conn(conn, DbKind::MySql, &record.tag)?;
+            let start = Instant::now();
+            let mut tx = conn.start_transaction(TxOpts::default())?;
+            self(&mut tx)?;
+            let duration_ms = start.elapsed().as_millis() as i64;
+            match *direction {
+                Direction::Up => insert_record(&mut tx, table, record, duration_ms)?,
+                Direction::Down => {
+                    tx.exec_drop(sql::mysql_delete_migration(table), (&record.tag,))?
+                }
+            };
+            tx.commit()?;
+            Ok(())
+        }
+    }
+
+    /// Execute a batch of statements
+    pub fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        conn.query_drop(stmt)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(())
+    }
+
+    /// Select rows of text columns
+    pub fn select_text_rows(conn: &mut Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let rows: Vec<::mysql::Row> = conn.query(stmt)?;
+        rows.iter()
+            .map(|row| {
+                (0..row.len())
+                    .map(|i| {
+                        row.get_opt::<Option<String>, _>(i)
+                            .unwrap_or(Ok(None))
+                            .map_err(|e| Error::from(format_err!(ErrorKind::Migration, "{}", e)))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect()
+    }
+
+    /// Name of the `GET_LOCK` lock for `table`. Lock names are server-wide,
+    /// so unqualified tables are scoped to the current database
+    fn lock_name(conn: &mut Conn, table: &MigrationTable) -> Result<String> {
+        let database: Option<String> = match table.schema {
+            Some(ref schema) => Some(schema.clone()),
+            None => conn
+                .query_first::<Option<String>, _>("select database()")?
+                .flatten(),
+        };
+        let scoped = MigrationTable {
+            schema: database,
+            name: table.name.clone(),
+        };
+        Ok(format!("migrant_{:016x}", lock_key(&scoped)))
+    }
+
+    /// Acquire the migration lock with `GET_LOCK`, waiting up to `timeout`
+    pub fn acquire_lock(conn: &mut Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        let name = lock_name(conn, table)?;
+        let acquired: Option<Option<i32>> =
+            conn.exec_first("select get_lock(?, ?)", (&name, timeout.as_secs()))?;
+        if acquired.flatten() != Some(1) {
+            bail!(lock_timeout_error(table, timeout))
+        }
+        Ok(())
+    }
+
+    /// Release the migration lock acquired on this connection
+    pub fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        let name = lock_name(conn, table)?;
+        conn.exec_drop("select release_lock(?)", (&name,))?;
+        Ok(())
+    }
 }
 
 pub use self::m::*;
 
+impl Driver for Conn {
+    fn connect(config: &Config) -> Result<Self> {
+        connect(&config.connect_string()?)
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::MySql)
+    }
+    fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_exists(self, table)
+    }
+    fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_setup(self, table)
+    }
+    fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_upgrade(self, table)
+    }
+    fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        select_migrations(self, table)
+    }
+    fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        select_checksums(self, table)
+    }
+    fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_migration_tag(self, table, record, duration_ms)
+    }
+    fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        remove_migration_tag(self, table, tag)
+    }
+    fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        execute_batch(self, stmt)
+    }
+    fn select_text_rows(&mut self, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        select_text_rows(self, stmt)
+    }
+    fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        apply_migration(self, table, stmt, record, direction, transactional)
+    }
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        acquire_lock(self, table, timeout)
+    }
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        release_lock(self, table)
+    }
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
+
 #[cfg(feature = "d-mysql")]
 #[cfg(test)]
 mod test {
@@ -153,42 +438,95 @@ mod test {
     fn mysql() {
         let conn_str = std::env::var("MYSQL_TEST_CONN_STR")
             .expect("MYSQL_TEST_CONN_STR env variable required");
+        let table = MigrationTable::default();
+        let mut conn = _try!(connect(&conn_str));
 
         // no table before setup
         can_connect(&conn_str).unwrap();
-        let is_setup = _try!(migration_table_exists(&conn_str));
+        let is_setup = _try!(migration_table_exists(&mut conn, &table));
         assert!(!is_setup, "Assert migration table does not exist");
 
         // setup migration table
-        let was_setup = _try!(migration_setup(&conn_str));
+        let was_setup = _try!(migration_setup(&mut conn, &table));
         assert!(
             was_setup,
             "Assert `migration_setup` initializes migration table"
         );
-        let was_setup = _try!(migration_setup(&conn_str));
+        let was_setup = _try!(migration_setup(&mut conn, &table));
         assert!(!was_setup, "Assert `migration_setup` is idempotent");
 
         // table exists after setup
-        let is_setup = _try!(migration_table_exists(&conn_str));
+        let is_setup = _try!(migration_table_exists(&mut conn, &table));
         assert!(is_setup, "Assert migration table exists");
 
         // insert some tags
-        _try!(insert_migration_tag(&conn_str, "initial"));
-        _try!(insert_migration_tag(&conn_str, "alter1"));
-        _try!(insert_migration_tag(&conn_str, "alter2"));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("initial"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("alter1"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("alter2"),
+            0
+        ));
 
         // get applied
-        let migs = _try!(select_migrations(&conn_str));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(3, migs.len(), "Assert 3 migrations applied");
 
         // remove some tags
-        _try!(remove_migration_tag(&conn_str, "alter2"));
-        let migs = _try!(select_migrations(&conn_str));
+        _try!(remove_migration_tag(&mut conn, &table, "alter2"));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(2, migs.len(), "Assert 2 migrations applied");
 
-        _try!(remove_migration_tag(&conn_str, "alter1"));
-        _try!(remove_migration_tag(&conn_str, "initial"));
-        let migs = _try!(select_migrations(&conn_str));
+        _try!(remove_migration_tag(&mut conn, &table, "alter1"));
+        _try!(remove_migration_tag(&mut conn, &table, "initial"));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(0, migs.len(), "Assert all migrations removed");
+
+        // statements and tag update are applied together
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "create table atomic(id integer);",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Up,
+            true
+        ));
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(1, migs.len(), "Assert migration applied");
+
+        // failed statements roll back the tag update
+        let res = apply_migration(
+            &mut conn,
+            &table,
+            "insert into atomic (id) values (1); not sql;",
+            &MigrationRecord::with_tag("broken"),
+            &Direction::Up,
+            true,
+        );
+        assert!(res.is_err(), "Assert invalid migration errors");
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(1, migs.len(), "Assert failed migration not recorded");
+
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "drop table atomic;",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Down,
+            true
+        ));
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(0, migs.len(), "Assert migration removed");
     }
 }
@@ -1,31 +1,57 @@
 use super::*;
+use crate::{Config, DbKind, Direction};
 /// Postgres database functions using shell commands and db drivers
 use std;
 use std::path::Path;
 
 #[cfg(feature = "d-postgres")]
-use postgres::{Client, NoTls};
+use postgres::{Client, GenericClient, NoTls};
+#[cfg(feature = "d-postgres")]
 use std::io::Read;
+#[cfg(feature = "d-postgres")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "d-postgres")]
+use std::time::Instant;
 
 #[cfg(not(feature = "d-postgres"))]
+#[allow(unused_variables)]
 mod m {
     use super::*;
+    pub struct Conn;
     pub fn can_connect(cert: Option<&Path>, conn_str: &str) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
-    pub fn migration_table_exists(cert: Option<&Path>, conn_str: &str) -> Result<bool> {
+    pub fn connect(cert: Option<&Path>, conn_str: &str) -> Result<Conn> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
-    pub fn migration_setup(cert: Option<&Path>, conn_str: &str) -> Result<bool> {
+    pub fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
-    pub fn select_migrations(cert: Option<&Path>, conn_str: &str) -> Result<Vec<String>> {
+    pub fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
-    pub fn insert_migration_tag(cert: Option<&Path>, conn_str: &str, tag: &str) -> Result<()> {
+    pub fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
-    pub fn remove_migration_tag(cert: Option<&Path>, conn_str: &str, tag: &str) -> Result<()> {
+    pub fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn remove_migration_tag(conn: &mut Conn, table: &MigrationTable, tag: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
     pub fn run_migration(cert: Option<&Path>, conn_str: &str, filename: &Path) -> Result<()> {
@@ -34,11 +60,36 @@ mod m {
     pub fn run_migration_str(cert: Option<&Path>, conn_str: &str, stmt: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-postgres feature");
     }
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn select_text_rows(conn: &mut Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn acquire_lock(conn: &mut Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
+    pub fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-postgres feature");
+    }
 }
 
 #[cfg(feature = "d-postgres")]
 mod m {
     use super::*;
+    use crate::migration::TxFn;
+    pub use postgres::Client as Conn;
+
     macro_rules! make_connector {
         ($file:expr) => {{
             let cert = std::fs::read($file)
@@ -78,54 +129,114 @@ mod m {
         }};
     }
 
-    /// Check `__migrant_migrations` table exists
-    pub fn migration_table_exists(cert: Option<&Path>, conn_str: &str) -> Result<bool> {
-        let mut conn = make_connection!(cert, conn_str)
-            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+    /// Open a connection to the database
+    pub fn connect(cert: Option<&Path>, conn_str: &str) -> Result<Conn> {
+        Ok(make_connection!(cert, conn_str)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?)
+    }
 
+    /// Check the migration table exists
+    pub fn migration_table_exists(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
         let rows = conn
-            .query(sql::PG_MIGRATION_TABLE_EXISTS, &[])
+            .query(&sql::pg_migration_table_exists(table), &[])
             .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         let exists: bool = rows
-            .get(0)
+            .first()
             .expect("expected a result from postgres when checking for migration table")
             .get(0);
         Ok(exists)
     }
 
-    /// Create `__migrant_migrations` table
-    pub fn migration_setup(cert: Option<&Path>, conn_str: &str) -> Result<bool> {
-        if !migration_table_exists(cert, conn_str)? {
-            let mut conn = make_connection!(cert, conn_str)
-                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
-            conn.execute(sql::CREATE_TABLE, &[])
+    /// Create the migration table
+    pub fn migration_setup(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        if !migration_table_exists(conn, table)? {
+            if let Some(ref schema) = table.schema {
+                conn.batch_execute(&sql::pg_create_schema(schema))
+                    .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+            }
+            conn.batch_execute(&sql::pg_create_table(table))
                 .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
             return Ok(true);
         }
         Ok(false)
     }
 
-    /// Select all migrations from `__migrant_migrations` table
-    pub fn select_migrations(cert: Option<&Path>, conn_str: &str) -> Result<Vec<String>> {
-        let mut conn = make_connection!(cert, conn_str)?;
-        let rows = conn.query(sql::GET_MIGRATIONS, &[])?;
+    /// Select all migrations from the migration table
+    pub fn select_migrations(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        let rows = conn.query(&sql::get_migrations(table), &[])?;
         Ok(rows.iter().map(|row| row.get(0)).collect())
     }
 
-    /// Insert migration tag into `__migrant_migrations` table
-    pub fn insert_migration_tag(cert: Option<&Path>, conn_str: &str, tag: &str) -> Result<()> {
-        let mut conn = make_connection!(cert, conn_str)?;
+    fn migration_columns(conn: &mut Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        Ok(conn
+            .query(&sql::pg_migration_columns(table), &[])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    /// Select the tags and checksums of migrations recorded with a checksum.
+    /// Returns nothing if the table hasn't been upgraded to store checksums.
+    pub fn select_checksums(
+        conn: &mut Conn,
+        table: &MigrationTable,
+    ) -> Result<Vec<(String, String)>> {
+        if !migration_columns(conn, table)?
+            .iter()
+            .any(|col| col == "checksum")
+        {
+            return Ok(vec![]);
+        }
+        let rows = conn.query(&sql::get_checksums(table), &[])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Add any columns missing from a migration table
+    /// created by an older version, returning true if the table was altered
+    pub fn migration_table_upgrade(conn: &mut Conn, table: &MigrationTable) -> Result<bool> {
+        let existing = migration_columns(conn, table)?;
+        let mut upgraded = false;
+        for &(name, kind) in sql::PG_COLUMNS {
+            if !existing.iter().any(|col| col == name) {
+                conn.batch_execute(&sql::add_column(table, name, kind))?;
+                upgraded = true;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    fn insert_record<C: GenericClient>(
+        conn: &mut C,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
         conn.execute(
-            "insert into __migrant_migrations (tag) values ($1)",
-            &[&tag],
+            &sql::insert_migration(table),
+            &[
+                &record.tag,
+                &duration_ms,
+                &record.applied_by,
+                &record.checksum,
+                &record.repeatable,
+            ],
         )?;
         Ok(())
     }
 
-    /// Delete migration tag from `__migrant_migrations` table
-    pub fn remove_migration_tag(cert: Option<&Path>, conn_str: &str, tag: &str) -> Result<()> {
-        let mut conn = make_connection!(cert, conn_str)?;
-        conn.execute("delete from __migrant_migrations where tag = $1", &[&tag])?;
+    /// Insert migration tag into the migration table
+    pub fn insert_migration_tag(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_record(conn, table, record, duration_ms)
+    }
+
+    /// Delete migration tag from the migration table
+    pub fn remove_migration_tag(conn: &mut Conn, table: &MigrationTable, tag: &str) -> Result<()> {
+        conn.execute(&sql::delete_migration(table), &[&tag])?;
         Ok(())
     }
 
@@ -149,10 +260,180 @@ mod m {
             .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         Ok(())
     }
+
+    fn run_and_tag<C: GenericClient>(
+        conn: &mut C,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        conn.batch_execute(stmt)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => insert_record(conn, table, record, duration_ms)?,
+            Direction::Down => {
+                conn.execute(&sql::delete_migration(table), &[&record.tag])?;
+            }
+        };
+        Ok(())
+    }
+
+    /// Apply migration statements and update the migration table
+    /// on a single connection. When `transactional`, both are committed together.
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        if transactional {
+            let mut tx = conn.transaction()?;
+            run_and_tag(&mut tx, table, stmt, record, direction)?;
+            tx.commit()?;
+        } else {
+            run_and_tag(conn, table, stmt, record, direction)?;
+        }
+        Ok(())
+    }
+
+    impl<F> TxFn<postgres::Transaction<'static>> for F
+    where
+        F: 'static
+            + Send
+            + Sync
+            + Fn(
+                &mut postgres::Transaction<'_>,
+            ) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    {
+        fn run_tx(
+            &self,
+            conn: &mut dyn Driver,
+            table: &MigrationTable,
+            record: &MigrationRecord,
+            direction: &Direction,
+        ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            let conn: &mut Conn = downcast_conn(conn, DbKind::Postgres, &record.tag)?;
+            let start = Instant::now();
+            let mut tx = conn.transaction()?;
+            self(&mut tx)?;
+            let duration_ms = start.elapsed().as_millis() as i64;
+            match *direction {
+                Direction::Up => insert_record(&mut tx, table, record, duration_ms)?,
+                Direction::Down => {
+                    tx.execute(&sql::delete_migration(table), &[&record.tag])?;
+                }
+            };
+            tx.commit()?;
+            Ok(())
+        }
+    }
+
+    /// Execute a batch of statements
+    pub fn execute_batch(conn: &mut Conn, stmt: &str) -> Result<()> {
+        conn.batch_execute(stmt)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(())
+    }
+
+    /// Select rows of text columns
+    pub fn select_text_rows(conn: &mut Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let rows = conn.query(stmt, &[])?;
+        Ok(rows
+            .iter()
+            .map(|row| (0..row.len()).map(|i| row.get(i)).collect())
+            .collect())
+    }
+
+    /// Acquire the migration lock as a session-level advisory lock, waiting up to `timeout`
+    pub fn acquire_lock(conn: &mut Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        let key = lock_key(table);
+        let start = Instant::now();
+        loop {
+            let row = conn.query_one("select pg_try_advisory_lock($1)", &[&key])?;
+            if row.get::<_, bool>(0) {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                bail!(lock_timeout_error(table, timeout))
+            }
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    /// Release the migration lock acquired on this connection
+    pub fn release_lock(conn: &mut Conn, table: &MigrationTable) -> Result<()> {
+        conn.execute("select pg_advisory_unlock($1)", &[&lock_key(table)])?;
+        Ok(())
+    }
 }
 
 pub use self::m::*;
 
+impl Driver for Conn {
+    fn connect(config: &Config) -> Result<Self> {
+        connect(config.ssl_cert_file().as_deref(), &config.connect_string()?)
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::Postgres)
+    }
+    fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_exists(self, table)
+    }
+    fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_setup(self, table)
+    }
+    fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_upgrade(self, table)
+    }
+    fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        select_migrations(self, table)
+    }
+    fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        select_checksums(self, table)
+    }
+    fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_migration_tag(self, table, record, duration_ms)
+    }
+    fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        remove_migration_tag(self, table, tag)
+    }
+    fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        execute_batch(self, stmt)
+    }
+    fn select_text_rows(&mut self, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        select_text_rows(self, stmt)
+    }
+    fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        apply_migration(self, table, stmt, record, direction, transactional)
+    }
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        acquire_lock(self, table, timeout)
+    }
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        release_lock(self, table)
+    }
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
+
 #[cfg(feature = "d-postgres")]
 #[cfg(test)]
 mod test {
@@ -174,42 +455,95 @@ mod test {
     fn postgres() {
         let conn_str = std::env::var("POSTGRES_TEST_CONN_STR")
             .expect("POSTGRES_TEST_CONN_STR env variable required");
+        let table = MigrationTable::default();
+        let mut conn = _try!(connect(None, &conn_str));
 
         // no table before setup
         assert!(can_connect(None, &conn_str).is_ok());
-        let is_setup = _try!(migration_table_exists(None, &conn_str));
+        let is_setup = _try!(migration_table_exists(&mut conn, &table));
         assert!(!is_setup, "Assert migration table does not exist");
 
         // setup migration table
-        let was_setup = _try!(migration_setup(None, &conn_str));
+        let was_setup = _try!(migration_setup(&mut conn, &table));
         assert!(
             was_setup,
             "Assert `migration_setup` initializes migration table"
         );
-        let was_setup = _try!(migration_setup(None, &conn_str));
+        let was_setup = _try!(migration_setup(&mut conn, &table));
         assert!(!was_setup, "Assert `migration_setup` is idempotent");
 
         // table exists after setup
-        let is_setup = _try!(migration_table_exists(None, &conn_str));
+        let is_setup = _try!(migration_table_exists(&mut conn, &table));
         assert!(is_setup, "Assert migration table exists");
 
         // insert some tags
-        _try!(insert_migration_tag(None, &conn_str, "initial"));
-        _try!(insert_migration_tag(None, &conn_str, "alter1"));
-        _try!(insert_migration_tag(None, &conn_str, "alter2"));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("initial"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("alter1"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &mut conn,
+            &table,
+            &MigrationRecord::with_tag("alter2"),
+            0
+        ));
 
         // get applied
-        let migs = _try!(select_migrations(None, &conn_str));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(3, migs.len(), "Assert 3 migrations applied");
 
         // remove some tags
-        _try!(remove_migration_tag(None, &conn_str, "alter2"));
-        let migs = _try!(select_migrations(None, &conn_str));
+        _try!(remove_migration_tag(&mut conn, &table, "alter2"));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(2, migs.len(), "Assert 2 migrations applied");
 
-        _try!(remove_migration_tag(None, &conn_str, "alter1"));
-        _try!(remove_migration_tag(None, &conn_str, "initial"));
-        let migs = _try!(select_migrations(None, &conn_str));
+        _try!(remove_migration_tag(&mut conn, &table, "alter1"));
+        _try!(remove_migration_tag(&mut conn, &table, "initial"));
+        let migs = _try!(select_migrations(&mut conn, &table));
         assert_eq!(0, migs.len(), "Assert all migrations removed");
+
+        // statements and tag update are applied together
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "create table atomic(id integer);",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Up,
+            true
+        ));
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(1, migs.len(), "Assert migration applied");
+
+        // failed statements roll back the tag update
+        let res = apply_migration(
+            &mut conn,
+            &table,
+            "insert into atomic (id) values (1); not sql;",
+            &MigrationRecord::with_tag("broken"),
+            &Direction::Up,
+            true,
+        );
+        assert!(res.is_err(), "Assert invalid migration errors");
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(1, migs.len(), "Assert failed migration not recorded");
+
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "drop table atomic;",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Down,
+            true
+        ));
+        let migs = _try!(select_migrations(&mut conn, &table));
+        assert_eq!(0, migs.len(), "Assert migration removed");
     }
 }
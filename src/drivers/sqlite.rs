@@ -1,30 +1,60 @@
 use super::*;
+use crate::{Config, DbKind, Direction};
+#[cfg(feature = "d-sqlite")]
 use std::fs;
 use std::path::Path;
 
 #[cfg(feature = "d-sqlite")]
-use rusqlite::Connection;
-use std::io::Read;
+use rusqlite::params;
+#[cfg(feature = "d-sqlite")]
+use std::collections::HashMap;
+#[cfg(feature = "d-sqlite")]
+use std::io::{Read, Write};
+#[cfg(feature = "d-sqlite")]
+use std::path::PathBuf;
+#[cfg(feature = "d-sqlite")]
+use std::sync::Mutex;
+#[cfg(feature = "d-sqlite")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "d-sqlite")]
+use std::time::Instant;
 
 #[cfg(not(feature = "d-sqlite"))]
+#[allow(unused_variables)]
 mod m {
     use super::*;
+    pub struct Conn;
     pub fn create_file_if_missing(path: &Path) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
-    pub fn migration_table_exists(db_path: &str) -> Result<bool> {
+    pub fn connect(db_path: &Path) -> Result<Conn> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn migration_table_exists(conn: &Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn migration_setup(conn: &Conn, table: &MigrationTable) -> Result<bool> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn select_migrations(conn: &Conn, table: &MigrationTable) -> Result<Vec<String>> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
-    pub fn migration_setup(db_path: &Path) -> Result<bool> {
+    pub fn select_checksums(conn: &Conn, table: &MigrationTable) -> Result<Vec<(String, String)>> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
-    pub fn select_migrations(db_path: &str) -> Result<Vec<String>> {
+    pub fn migration_table_upgrade(conn: &Conn, table: &MigrationTable) -> Result<bool> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
-    pub fn insert_migration_tag(db_path: &str, tag: &str) -> Result<()> {
+    pub fn insert_migration_tag(
+        conn: &Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
-    pub fn remove_migration_tag(db_path: &str, tag: &str) -> Result<()> {
+    pub fn remove_migration_tag(conn: &Conn, table: &MigrationTable, tag: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
     pub fn run_migration(db_path: &Path, filename: &Path) -> Result<()> {
@@ -33,11 +63,36 @@ mod m {
     pub fn run_migration_str(db_path: &Path, stmt: &str) -> Result<()> {
         unimplemented!("migrant_lib: must enable d-sqlite feature");
     }
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn execute_batch(conn: &Conn, stmt: &str) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn select_text_rows(conn: &Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn acquire_lock(conn: &Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
+    pub fn release_lock(conn: &Conn, table: &MigrationTable) -> Result<()> {
+        unimplemented!("migrant_lib: must enable d-sqlite feature");
+    }
 }
 
 #[cfg(feature = "d-sqlite")]
 mod m {
     use super::*;
+    use crate::migration::TxFn;
+    pub use rusqlite::Connection as Conn;
+
     /// Check database exists / create it
     /// Create a file if it doesn't exist, returning true if the file was created
     pub fn create_file_if_missing(path: &Path) -> Result<bool> {
@@ -59,29 +114,32 @@ mod m {
         }
     }
 
-    /// Check `__migrant_migrations` table exists
-    pub fn migration_table_exists(db_path: &str) -> Result<bool> {
-        let conn = Connection::open(db_path)?;
+    /// Open a connection to the database file
+    pub fn connect(db_path: &Path) -> Result<Conn> {
+        Ok(Conn::open(db_path)?)
+    }
+
+    /// Check the migration table exists
+    pub fn migration_table_exists(conn: &Conn, table: &MigrationTable) -> Result<bool> {
         let exists: bool =
-            conn.query_row(sql::SQLITE_MIGRATION_TABLE_EXISTS, [], |row| row.get(0))?;
+            conn.query_row(&sql::sqlite_migration_table_exists(table), [], |row| {
+                row.get(0)
+            })?;
         Ok(exists)
     }
 
-    /// Create `__migrant_migrations` table
-    pub fn migration_setup(db_path: &Path) -> Result<bool> {
-        let db_path = db_path.to_str().unwrap();
-        if !migration_table_exists(db_path)? {
-            let conn = Connection::open(db_path)?;
-            conn.execute(sql::CREATE_TABLE, [])?;
+    /// Create the migration table
+    pub fn migration_setup(conn: &Conn, table: &MigrationTable) -> Result<bool> {
+        if !migration_table_exists(conn, table)? {
+            conn.execute_batch(&sql::create_table(table))?;
             return Ok(true);
         }
         Ok(false)
     }
 
-    /// Select all migrations from `__migrant_migrations` table
-    pub fn select_migrations(db_path: &str) -> Result<Vec<String>> {
-        let conn = Connection::open(db_path)?;
-        let mut stmt = conn.prepare(sql::GET_MIGRATIONS)?;
+    /// Select all migrations from the migration table
+    pub fn select_migrations(conn: &Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        let mut stmt = conn.prepare(&sql::sqlite_get_migrations(table))?;
         let mut rows = stmt.query([])?;
         let mut migs = vec![];
         while let Some(row) = rows.next()? {
@@ -90,20 +148,67 @@ mod m {
         Ok(migs)
     }
 
-    /// Insert tag into `__migrant_migrations` table
-    pub fn insert_migration_tag(db_path: &str, tag: &str) -> Result<()> {
-        let conn = Connection::open(db_path)?;
+    fn migration_columns(conn: &Conn, table: &MigrationTable) -> Result<Vec<String>> {
+        let mut stmt = conn.prepare(&sql::sqlite_migration_columns(table))?;
+        let columns = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, _>>()?;
+        Ok(columns)
+    }
+
+    /// Select the tags and checksums of migrations recorded with a checksum.
+    /// Returns nothing if the table hasn't been upgraded to store checksums.
+    pub fn select_checksums(conn: &Conn, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        if !migration_columns(conn, table)?
+            .iter()
+            .any(|col| col == "checksum")
+        {
+            return Ok(vec![]);
+        }
+        let mut stmt = conn.prepare(&sql::get_checksums(table))?;
+        let checksums = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(checksums)
+    }
+
+    /// Add any columns missing from a migration table
+    /// created by an older version, returning true if the table was altered
+    pub fn migration_table_upgrade(conn: &Conn, table: &MigrationTable) -> Result<bool> {
+        let existing = migration_columns(conn, table)?;
+        let mut upgraded = false;
+        for &(name, kind) in sql::COLUMNS {
+            if !existing.iter().any(|col| col == name) {
+                conn.execute_batch(&sql::add_column(table, name, kind))?;
+                upgraded = true;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    /// Insert tag into the migration table
+    pub fn insert_migration_tag(
+        conn: &Conn,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
         conn.execute(
-            "insert into __migrant_migrations (tag) values ($1)",
-            &[&tag],
+            &sql::insert_migration(table),
+            params![
+                record.tag,
+                duration_ms,
+                record.applied_by,
+                record.checksum,
+                record.repeatable
+            ],
         )?;
         Ok(())
     }
 
-    /// Remove tag from `__migrant_migrations` table
-    pub fn remove_migration_tag(db_path: &str, tag: &str) -> Result<()> {
-        let conn = Connection::open(db_path)?;
-        conn.execute("delete from __migrant_migrations where tag = $1", &[&tag])?;
+    /// Remove tag from the migration table
+    pub fn remove_migration_tag(conn: &Conn, table: &MigrationTable, tag: &str) -> Result<()> {
+        conn.execute(&sql::delete_migration(table), [&tag])?;
         Ok(())
     }
 
@@ -116,8 +221,7 @@ mod m {
             return Ok(());
         }
 
-        let conn =
-            Connection::open(db_path).map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let conn = Conn::open(db_path).map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         conn.execute_batch(&buf)
             .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         Ok(())
@@ -128,16 +232,239 @@ mod m {
             return Ok(());
         }
 
-        let conn =
-            Connection::open(db_path).map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        let conn = Conn::open(db_path).map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         conn.execute_batch(stmt)
             .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
         Ok(())
     }
+
+    fn run_and_tag(
+        conn: &Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        if !stmt.is_empty() {
+            conn.execute_batch(stmt)
+                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        }
+        let duration_ms = start.elapsed().as_millis() as i64;
+        match *direction {
+            Direction::Up => insert_migration_tag(conn, table, record, duration_ms)?,
+            Direction::Down => remove_migration_tag(conn, table, &record.tag)?,
+        };
+        Ok(())
+    }
+
+    /// Apply migration statements and update the migration table
+    /// on a single connection. When `transactional`, both are committed together.
+    pub fn apply_migration(
+        conn: &mut Conn,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        if transactional {
+            let tx = conn.transaction()?;
+            run_and_tag(&tx, table, stmt, record, direction)?;
+            tx.commit()?;
+        } else {
+            run_and_tag(conn, table, stmt, record, direction)?;
+        }
+        Ok(())
+    }
+
+    impl<F> TxFn<rusqlite::Transaction<'static>> for F
+    where
+        F: 'static
+            + Send
+            + Sync
+            + Fn(&rusqlite::Transaction<'_>) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    {
+        fn run_tx(
+            &self,
+            conn: &mut dyn Driver,
+            table: &MigrationTable,
+            record: &MigrationRecord,
+            direction: &Direction,
+        ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            let conn: &mut Conn = downcast_conn(conn, DbKind::Sqlite, &record.tag)?;
+            let start = Instant::now();
+            let tx = conn.transaction()?;
+            self(&tx)?;
+            let duration_ms = start.elapsed().as_millis() as i64;
+            match *direction {
+                Direction::Up => insert_migration_tag(&tx, table, record, duration_ms)?,
+                Direction::Down => remove_migration_tag(&tx, table, &record.tag)?,
+            };
+            tx.commit()?;
+            Ok(())
+        }
+    }
+
+    /// Execute a batch of statements
+    pub fn execute_batch(conn: &Conn, stmt: &str) -> Result<()> {
+        conn.execute_batch(stmt)
+            .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+        Ok(())
+    }
+
+    /// Select rows of text columns
+    pub fn select_text_rows(conn: &Conn, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let mut stmt = conn.prepare(stmt)?;
+        let count = stmt.column_count();
+        let rows = stmt
+            .query_map([], |row| (0..count).map(|i| row.get(i)).collect())?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Path of the lock file for `table`, or `None` for in-memory databases
+    fn lock_path(conn: &Conn, table: &MigrationTable) -> Option<PathBuf> {
+        match conn.path() {
+            Some(path) if !path.is_empty() => {
+                Some(PathBuf::from(format!("{}.{}.lock", path, table)))
+            }
+            _ => None,
+        }
+    }
+
+    lazy_static! {
+        /// Lock files held by this process, keyed by path. Dropping a file releases its lock.
+        static ref HELD_LOCKS: Mutex<HashMap<PathBuf, fs::File>> = Mutex::new(HashMap::new());
+    }
+
+    /// Acquire the migration lock by taking an exclusive OS lock on a
+    /// `<database>.<table>.lock` file, waiting up to `timeout` for another
+    /// process to release it.
+    ///
+    /// The lock is released by the OS if the process exits without calling `release_lock`,
+    /// so a lock file left behind by a crashed run doesn't block later runs and can be
+    /// safely deleted. In-memory databases can't be shared between processes,
+    /// so no lock is taken.
+    pub fn acquire_lock(conn: &Conn, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        let path = match lock_path(conn, table) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .chain_err(|| format!("Failed opening lock file: {:?}", path))?;
+        let start = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(fs::TryLockError::WouldBlock) => {
+                    if start.elapsed() >= timeout {
+                        bail_fmt!(
+                            ErrorKind::LockTimeout,
+                            "Unable to acquire the migration lock for `{}` within {:?}, \
+                             another process holding the lock file {:?} may be running migrations",
+                            table,
+                            timeout,
+                            path
+                        )
+                    }
+                    thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(fs::TryLockError::Error(e)) => {
+                    return Err(e).chain_err(|| format!("Failed locking lock file: {:?}", path))
+                }
+            }
+        }
+        file.set_len(0)
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .chain_err(|| format!("Failed writing lock file: {:?}", path))?;
+        HELD_LOCKS
+            .lock()
+            .map_err(|_| format_err!(ErrorKind::Migration, "sqlite lock registry poisoned"))?
+            .insert(path, file);
+        Ok(())
+    }
+
+    /// Release the migration lock held by this process. The lock file itself is left
+    /// in place, removing it could let another process lock a file that's about to be replaced.
+    pub fn release_lock(conn: &Conn, table: &MigrationTable) -> Result<()> {
+        if let Some(path) = lock_path(conn, table) {
+            HELD_LOCKS
+                .lock()
+                .map_err(|_| format_err!(ErrorKind::Migration, "sqlite lock registry poisoned"))?
+                .remove(&path);
+        }
+        Ok(())
+    }
 }
 
 pub use self::m::*;
 
+impl Driver for Conn {
+    fn connect(config: &Config) -> Result<Self> {
+        connect(&config.database_path()?)
+    }
+    fn database_type(&self) -> Option<DbKind> {
+        Some(DbKind::Sqlite)
+    }
+    fn migration_table_exists(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_exists(self, table)
+    }
+    fn migration_setup(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_setup(self, table)
+    }
+    fn migration_table_upgrade(&mut self, table: &MigrationTable) -> Result<bool> {
+        migration_table_upgrade(self, table)
+    }
+    fn select_migrations(&mut self, table: &MigrationTable) -> Result<Vec<String>> {
+        select_migrations(self, table)
+    }
+    fn select_checksums(&mut self, table: &MigrationTable) -> Result<Vec<(String, String)>> {
+        select_checksums(self, table)
+    }
+    fn insert_migration_tag(
+        &mut self,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        duration_ms: i64,
+    ) -> Result<()> {
+        insert_migration_tag(self, table, record, duration_ms)
+    }
+    fn remove_migration_tag(&mut self, table: &MigrationTable, tag: &str) -> Result<()> {
+        remove_migration_tag(self, table, tag)
+    }
+    fn execute_batch(&mut self, stmt: &str) -> Result<()> {
+        execute_batch(self, stmt)
+    }
+    fn select_text_rows(&mut self, stmt: &str) -> Result<Vec<Vec<Option<String>>>> {
+        select_text_rows(self, stmt)
+    }
+    fn apply_migration(
+        &mut self,
+        table: &MigrationTable,
+        stmt: &str,
+        record: &MigrationRecord,
+        direction: &Direction,
+        transactional: bool,
+    ) -> Result<()> {
+        apply_migration(self, table, stmt, record, direction, transactional)
+    }
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Duration) -> Result<()> {
+        acquire_lock(self, table, timeout)
+    }
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        release_lock(self, table)
+    }
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
+
 #[cfg(feature = "d-sqlite")]
 #[cfg(test)]
 mod test {
@@ -159,41 +486,121 @@ mod test {
         let conn_str =
             std::env::var("SQLITE_TEST_CONN_STR").expect("SQLITE_TEST_CONN_STR env var required");
         let path = std::path::Path::new(&conn_str);
+        let table = MigrationTable::default();
+        let mut conn = _try!(connect(path));
 
         // no table before setup
-        let is_setup = _try!(migration_table_exists(&conn_str));
+        let is_setup = _try!(migration_table_exists(&conn, &table));
         assert!(!is_setup, "Assert migration table does not exist");
 
         // setup migration table
-        let was_setup = _try!(migration_setup(path));
+        let was_setup = _try!(migration_setup(&conn, &table));
         assert!(
             was_setup,
             "Assert `migration_setup` initializes migration table"
         );
-        let was_setup = _try!(migration_setup(path));
+        let was_setup = _try!(migration_setup(&conn, &table));
         assert!(!was_setup, "Assert `migration_setup` is idempotent");
 
         // table exists after setup
-        let is_setup = _try!(migration_table_exists(&conn_str));
+        let is_setup = _try!(migration_table_exists(&conn, &table));
         assert!(is_setup, "Assert migration table exists");
 
         // insert some tags
-        _try!(insert_migration_tag(&conn_str, "initial"));
-        _try!(insert_migration_tag(&conn_str, "alter1"));
-        _try!(insert_migration_tag(&conn_str, "alter2"));
+        _try!(insert_migration_tag(
+            &conn,
+            &table,
+            &MigrationRecord::with_tag("initial"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &conn,
+            &table,
+            &MigrationRecord::with_tag("alter1"),
+            0
+        ));
+        _try!(insert_migration_tag(
+            &conn,
+            &table,
+            &MigrationRecord::with_tag("alter2"),
+            0
+        ));
 
         // get applied
-        let migs = _try!(select_migrations(&conn_str));
+        let migs = _try!(select_migrations(&conn, &table));
         assert_eq!(3, migs.len(), "Assert 3 migrations applied");
 
         // remove some tags
-        _try!(remove_migration_tag(&conn_str, "alter2"));
-        let migs = _try!(select_migrations(&conn_str));
+        _try!(remove_migration_tag(&conn, &table, "alter2"));
+        let migs = _try!(select_migrations(&conn, &table));
         assert_eq!(2, migs.len(), "Assert 2 migrations applied");
 
-        _try!(remove_migration_tag(&conn_str, "alter1"));
-        _try!(remove_migration_tag(&conn_str, "initial"));
-        let migs = _try!(select_migrations(&conn_str));
+        _try!(remove_migration_tag(&conn, &table, "alter1"));
+        _try!(remove_migration_tag(&conn, &table, "initial"));
+        let migs = _try!(select_migrations(&conn, &table));
         assert_eq!(0, migs.len(), "Assert all migrations removed");
+
+        // statements and tag update are applied together
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "create table atomic(id integer);",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Up,
+            true
+        ));
+        let migs = _try!(select_migrations(&conn, &table));
+        assert_eq!(1, migs.len(), "Assert migration applied");
+
+        // failed statements roll back the tag update
+        let res = apply_migration(
+            &mut conn,
+            &table,
+            "insert into atomic (id) values (1); not sql;",
+            &MigrationRecord::with_tag("broken"),
+            &Direction::Up,
+            true,
+        );
+        assert!(res.is_err(), "Assert invalid migration errors");
+        let migs = _try!(select_migrations(&conn, &table));
+        assert_eq!(1, migs.len(), "Assert failed migration not recorded");
+
+        _try!(apply_migration(
+            &mut conn,
+            &table,
+            "drop table atomic;",
+            &MigrationRecord::with_tag("atomic"),
+            &Direction::Down,
+            true
+        ));
+        let migs = _try!(select_migrations(&conn, &table));
+        assert_eq!(0, migs.len(), "Assert migration removed");
+    }
+
+    #[test]
+    fn lock_file() {
+        let path =
+            std::env::temp_dir().join(format!("migrant_lib_test_{}_lock.db", std::process::id()));
+        let table = MigrationTable::default();
+        let first = _try!(connect(&path));
+        let second = _try!(connect(&path));
+        let lock_path = std::path::PathBuf::from(format!("{}.{}.lock", path.display(), table));
+
+        _try!(acquire_lock(&first, &table, Duration::from_secs(0)));
+        let err = acquire_lock(&second, &table, Duration::from_secs(0))
+            .expect_err("Assert a held lock can't be acquired");
+        assert!(err.is_lock_timeout(), "Assert lock timeout error");
+        assert!(
+            err.to_string().contains(&format!("{:?}", lock_path)),
+            "Assert lock timeout error names the lock file"
+        );
+
+        _try!(release_lock(&first, &table));
+        assert!(lock_path.exists(), "Assert lock file is left in place");
+        _try!(acquire_lock(&second, &table, Duration::from_secs(0)));
+        _try!(release_lock(&second, &table));
+
+        let _ = std::fs::remove_file(&lock_path);
+        let _ = std::fs::remove_file(&path);
     }
 }
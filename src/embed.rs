@@ -0,0 +1,247 @@
+/*!
+Embedding a directory of migrations at compile time
+
+Call `embed::generate` from your crate's build script to find the migrations of a directory,
+following the same rules as the `migrant` CLI tool, and `embed_migrations!` to include them
+as `EmbeddedMigration`s with cli compatible tags. Migrations added with `migrant new` are
+picked up on the next build without listing each file.
+
+```rust,no_run
+// build.rs
+extern crate migrant_lib;
+
+fn main() {
+    migrant_lib::embed::generate("migrations").expect("failed embedding migrations");
+}
+```
+
+```rust,ignore
+// main.rs
+let mut config = Config::with_settings(&settings);
+config.use_cli_compatible_tags(true);
+config.use_migrations(&migrant_lib::embed_migrations!("migrations"))?;
+```
+
+`migrant_lib` must be listed under both `[dependencies]`, with a database feature enabled,
+and `[build-dependencies]`.
+
+Only the migrant layout, `MigrationFormat::Migrant`, can be embedded. Tags of the other
+layouts are their file names, which `Config::use_migrations` doesn't accept, so a configured
+`migration_format` is ignored.
+*/
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::errors::*;
+use crate::migratable::Migratable;
+use crate::migration::split_sections;
+use crate::{
+    search_for_migrations, search_for_repeatable_migrations, write_to_path, MigrationFormat,
+};
+
+/// Directory under `OUT_DIR` where generated migration lists are written
+static EMBED_DIR: &str = "migrant_embedded";
+
+/// Find the migrations under `migration_dir`, relative to the crate root, and write the
+/// source included by `embed_migrations!` for the same `migration_dir` to `OUT_DIR`.
+///
+/// Must be called from a build script. Cargo is told to re-run the build script
+/// whenever anything under `migration_dir` changes. Single-file migrations are
+/// checked for valid `-- migrate:up` and `-- migrate:down` sections.
+///
+/// `migration_dir` is always searched as a `MigrationFormat::Migrant` layout.
+pub fn generate<T: AsRef<Path>>(migration_dir: T) -> Result<()> {
+    let migration_dir = migration_dir.as_ref();
+    let manifest_dir = build_env("CARGO_MANIFEST_DIR")?;
+    let out_dir = build_env("OUT_DIR")?;
+
+    let mig_root = Path::new(&manifest_dir).join(migration_dir);
+    if !mig_root.is_dir() {
+        bail_fmt!(
+            ErrorKind::PathError,
+            "Migration directory not found: {:?}",
+            mig_root
+        )
+    }
+    println!("cargo:rerun-if-changed={}", mig_root.display());
+
+    let source = render_migrations(&mig_root)?;
+    let out_path = Path::new(&out_dir)
+        .join(EMBED_DIR)
+        .join(migration_dir)
+        .join("migrations.rs");
+    fs::create_dir_all(out_path.parent().unwrap())?;
+    write_to_path(&out_path, &source)
+}
+
+fn build_env(var: &str) -> Result<String> {
+    env::var(var).map_err(|_| {
+        format_err!(
+            ErrorKind::Config,
+            "`{}` is not set, `embed::generate` must be called from a build script",
+            var
+        )
+        .into()
+    })
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| format_err!(ErrorKind::PathError, "Invalid utf8 path: {:?}", path).into())
+}
+
+/// Render an expression building a `Vec<Box<dyn Migratable>>` of `EmbeddedMigration`s and
+/// `RepeatableMigration`s that include the contents of each migration found under `mig_root`,
+/// in order
+fn render_migrations(mig_root: &Path) -> Result<Vec<u8>> {
+    let mut source = vec![];
+    writeln!(
+        source,
+        "{{\n    #[allow(unused_mut)]\n    let mut migrations: \
+         ::std::vec::Vec<::std::boxed::Box<dyn ::migrant_lib::Migratable>> = \
+         ::std::vec::Vec::new();"
+    )?;
+    for mig in search_for_migrations(mig_root, MigrationFormat::Migrant)? {
+        let tag = mig.tag();
+        writeln!(
+            source,
+            "    migrations.push(::migrant_lib::EmbeddedMigration::with_tag({:?})",
+            tag
+        )?;
+        if mig.single_file {
+            let up = mig
+                .up
+                .as_ref()
+                .expect("single-file migrations always have a file");
+            split_sections(&fs::read_to_string(up)?)
+                .chain_err(|| format!("Invalid migration file: {:?}", up))?;
+            writeln!(
+                source,
+                "        .from_str(include_str!({:?}))\n        \
+                 .expect(\"embedded migration sections are checked by `embed::generate`\")",
+                path_str(up)?
+            )?;
+        } else {
+            let files = [("up", &mig.up), ("down", &mig.down)];
+            for (method, file) in files.iter() {
+                if let Some(file) = file {
+                    writeln!(
+                        source,
+                        "        .{}(include_str!({:?}))",
+                        method,
+                        path_str(file)?
+                    )?;
+                }
+            }
+            let variants = [("up_for", &mig.up_for), ("down_for", &mig.down_for)];
+            for (method, variants) in variants.iter() {
+                for (db_kind, file) in variants.iter() {
+                    writeln!(
+                        source,
+                        "        .{}(::migrant_lib::DbKind::{:?}, include_str!({:?}))",
+                        method,
+                        db_kind,
+                        path_str(file)?
+                    )?;
+                }
+            }
+        }
+        writeln!(source, "        .boxed());")?;
+    }
+    for mig in search_for_repeatable_migrations(mig_root, MigrationFormat::Migrant)? {
+        let file = mig
+            .file
+            .as_ref()
+            .expect("repeatable file migrations always have a file");
+        writeln!(
+            source,
+            "    migrations.push(::migrant_lib::RepeatableMigration::with_tag({:?})\n        \
+             .sql(include_str!({:?}))\n        .boxed());",
+            mig.tag,
+            path_str(file)?
+        )?;
+    }
+    writeln!(source, "    migrations\n}}")?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::fixture_dir;
+
+    #[test]
+    fn render_migrant_layout() {
+        let dir = fixture_dir(
+            "render_migrations",
+            &[
+                (
+                    "20200101000000_create-users/up.sql",
+                    "create table users(id int);",
+                ),
+                ("20200101000000_create-users/down.sql", "drop table users;"),
+                (
+                    "20200101000000_create-users/up.postgres.sql",
+                    "create table users(id serial);",
+                ),
+                (
+                    "20200102000000_add-email.sql",
+                    "-- migrate:up\nalter table users add email text;\n\
+                     -- migrate:down\nalter table users drop email;\n",
+                ),
+                (
+                    "repeatable/active-users.sql",
+                    "create view active_users as select 1;",
+                ),
+            ],
+        );
+        let source = String::from_utf8(render_migrations(&dir).unwrap()).unwrap();
+        let file = |name: &str| format!("{:?}", dir.join(name).to_str().unwrap());
+        let expected = format!(
+            "{{
+    #[allow(unused_mut)]
+    let mut migrations: ::std::vec::Vec<::std::boxed::Box<dyn ::migrant_lib::Migratable>> = ::std::vec::Vec::new();
+    migrations.push(::migrant_lib::EmbeddedMigration::with_tag(\"20200101000000_create-users\")
+        .up(include_str!({}))
+        .down(include_str!({}))
+        .up_for(::migrant_lib::DbKind::Postgres, include_str!({}))
+        .boxed());
+    migrations.push(::migrant_lib::EmbeddedMigration::with_tag(\"20200102000000_add-email\")
+        .from_str(include_str!({}))
+        .expect(\"embedded migration sections are checked by `embed::generate`\")
+        .boxed());
+    migrations.push(::migrant_lib::RepeatableMigration::with_tag(\"active-users\")
+        .sql(include_str!({}))
+        .boxed());
+    migrations
+}}
+",
+            file("20200101000000_create-users/up.sql"),
+            file("20200101000000_create-users/down.sql"),
+            file("20200101000000_create-users/up.postgres.sql"),
+            file("20200102000000_add-email.sql"),
+            file("repeatable/active-users.sql"),
+        );
+        assert_eq!(
+            expected, source,
+            "Assert directory, per-database, single-file and repeatable migrations are embedded in order"
+        );
+    }
+
+    #[test]
+    fn render_invalid_single_file() {
+        let dir = fixture_dir(
+            "render_invalid_single_file",
+            &[(
+                "20200101000000_create-users.sql",
+                "create table users(id int);",
+            )],
+        );
+        assert!(
+            render_migrations(&dir).is_err(),
+            "Assert single-file migrations without sections aren't embedded"
+        );
+    }
+}
@@ -30,6 +30,7 @@ error_chain! {
         Sqlite(rusqlite::Error) #[cfg(feature="d-sqlite")];
         Postgres(postgres::Error) #[cfg(feature="d-postgres")];
         MySql(mysql::Error) #[cfg(feature="d-mysql")];
+        MySqlAsync(mysql_async::Error) #[cfg(feature="async-mysql")];
     }
     errors {
         Config(s: String) {
@@ -48,6 +49,14 @@ error_chain! {
             description("MigrationNotFound")
             display("MigrationNotFound: {}", s)
         }
+        ChecksumMismatch(s: String) {
+            description("ChecksumMismatch")
+            display("ChecksumMismatch: {}", s)
+        }
+        LockTimeout(s: String) {
+            description("LockTimeout")
+            display("LockTimeout: {}", s)
+        }
         ShellCommand(s: String) {
             description("ShellCommand")
             display("ShellCommandError: {}", s)
@@ -77,6 +86,16 @@ impl Error {
         matches!(*self.kind(), ErrorKind::MigrationComplete(_))
     }
 
+    /// Return `true` if the `ErrorKind` is `ErrorKind::ChecksumMismatch`
+    pub fn is_checksum_mismatch(&self) -> bool {
+        matches!(*self.kind(), ErrorKind::ChecksumMismatch(_))
+    }
+
+    /// Return `true` if the `ErrorKind` is `ErrorKind::LockTimeout`
+    pub fn is_lock_timeout(&self) -> bool {
+        matches!(*self.kind(), ErrorKind::LockTimeout(_))
+    }
+
     /// Return `true` if the `ErrorKind` is `ErrorKind::ShellCommandNoOutput`
     ///
     /// This error only arises when using `mysql` is "shell-wrapping" mode where
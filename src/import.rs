@@ -0,0 +1,401 @@
+/*!
+Importing applied migrations from other migration tools
+
+*/
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::drivers::{render_migration_tag, Driver, MigrationRecord, MigrationTable};
+use crate::errors::*;
+use crate::migratable::Migratable;
+use crate::{Config, DbKind, Direction};
+
+/// How long to wait for the migration lock while importing
+static IMPORT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Migration tool whose tracking table applied migrations are imported from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ImportSource {
+    /// diesel's `__diesel_schema_migrations`
+    Diesel,
+    /// sqlx's `_sqlx_migrations`. Only successful migrations are imported
+    Sqlx,
+    /// refinery's `refinery_schema_history`
+    Refinery,
+    /// Flyway's `flyway_schema_history`. Only successful versioned migrations that
+    /// haven't been undone are imported
+    Flyway,
+}
+impl ImportSource {
+    /// Default name of the tool's tracking table
+    pub fn default_table(&self) -> &'static str {
+        match *self {
+            ImportSource::Diesel => "__diesel_schema_migrations",
+            ImportSource::Sqlx => "_sqlx_migrations",
+            ImportSource::Refinery => "refinery_schema_history",
+            ImportSource::Flyway => "flyway_schema_history",
+        }
+    }
+
+    /// Query selecting the `version`, `name`, `script` and an undo marker
+    /// of each recorded migration as text, in the order they were run
+    fn select_query(&self, table: &MigrationTable, db_kind: Option<DbKind>) -> String {
+        let text = match db_kind {
+            Some(DbKind::MySql) => "char",
+            _ => "text",
+        };
+        let null = format!("cast(null as {})", text);
+        match *self {
+            ImportSource::Diesel => format!(
+                "select cast(version as {text}), {null}, {null}, {null} from {table} \
+                 order by version;",
+                text = text,
+                null = null,
+                table = table
+            ),
+            ImportSource::Sqlx => format!(
+                "select cast(version as {text}), description, {null}, {null} from {table} \
+                 where success order by version;",
+                text = text,
+                null = null,
+                table = table
+            ),
+            ImportSource::Refinery => format!(
+                "select cast(version as {text}), name, {null}, {null} from {table} \
+                 order by version;",
+                text = text,
+                null = null,
+                table = table
+            ),
+            ImportSource::Flyway => format!(
+                "select cast(version as {text}), description, script, \
+                 case when type like 'UNDO%' then 'undo' end from {table} \
+                 where success and version is not null order by installed_rank;",
+                text = text,
+                table = table
+            ),
+        }
+    }
+}
+impl fmt::Display for ImportSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportSource::Diesel => write!(f, "diesel"),
+            ImportSource::Sqlx => write!(f, "sqlx"),
+            ImportSource::Refinery => write!(f, "refinery"),
+            ImportSource::Flyway => write!(f, "flyway"),
+        }
+    }
+}
+
+/// A migration recorded as applied by another migration tool
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedMigration {
+    /// Version of the migration, e.g. `20200101120000` or `1.2`
+    pub version: String,
+    /// Name or description of the migration, if recorded
+    pub name: Option<String>,
+    /// Script file name of the migration, if recorded (Flyway)
+    pub script: Option<String>,
+}
+impl ImportedMigration {
+    /// Return the migrant tag matching this migration by default: the available migration
+    /// whose tag is the script's file name without extension, the version, or starts with
+    /// the version followed by `_`
+    fn default_tag(&self, available: &[Box<dyn Migratable>]) -> Option<String> {
+        let script = self
+            .script
+            .as_ref()
+            .map(|script| script.trim_end_matches(".sql"));
+        let prefix = format!("{}_", self.version);
+        available.iter().map(|mig| mig.tag()).find(|tag| {
+            Some(tag.as_str()) == script || *tag == self.version || tag.starts_with(&prefix)
+        })
+    }
+}
+
+type TagMapping = Rc<dyn Fn(&ImportedMigration) -> Option<String>>;
+
+/// Import the applied migrations recorded by diesel, sqlx, refinery or Flyway
+/// into the migration table, so existing databases can switch to migrant without
+/// `fake`-applying every migration.
+///
+/// Each imported migration is mapped to the tag of an available migration. By default the
+/// available migration whose tag is the script's file name (Flyway), the version, or starts
+/// with `<version>_` is used. Use `Importer::map` to supply a mapping, returning `None` to
+/// skip a migration. Migrations that are already applied are skipped.
+///
+/// The migration table must already exist, see `Config::setup`.
+///
+/// ```rust,no_run
+/// # extern crate migrant_lib;
+/// # use migrant_lib::{Config, Importer, ImportSource};
+/// # fn main() { run().unwrap(); }
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let config = Config::from_settings_file("Migrant.toml")?;
+/// config.setup()?;
+/// // sqlx records `20200101120000` & `create users` for `20200101120000_create users.sql`,
+/// // the migrant tag is `20200101120000_create-users`
+/// let imported = Importer::with_source(ImportSource::Sqlx)
+///     .map(|mig| Some(format!("{}_{}", mig.version, mig.name.as_ref()?.replace(' ', "-"))))
+///     .import(&config)?;
+/// println!("imported: {:?}", imported);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Importer {
+    source: ImportSource,
+    table: Option<String>,
+    mapping: Option<TagMapping>,
+}
+impl Importer {
+    /// Initialize a new `Importer` reading from the given tool's tracking table
+    pub fn with_source(source: ImportSource) -> Self {
+        Self {
+            source,
+            table: None,
+            mapping: None,
+        }
+    }
+
+    /// Set the tracking table to read, when the tool was configured to use a table other
+    /// than `ImportSource::default_table`. May be qualified with a schema
+    pub fn table(&mut self, table: &str) -> &mut Self {
+        self.table = Some(table.into());
+        self
+    }
+
+    /// Set the mapping from an imported migration to the tag of an available
+    /// migration, or `None` to skip it
+    pub fn map<F>(&mut self, mapping: F) -> &mut Self
+    where
+        F: Fn(&ImportedMigration) -> Option<String> + 'static,
+    {
+        self.mapping = Some(Rc::new(mapping));
+        self
+    }
+
+    /// Import applied migrations, returning the tags that were recorded as applied
+    ///
+    /// Returns an `ErrorKind::MigrationNotFound` if an imported migration is mapped
+    /// to a tag that isn't an available migration, in which case nothing is recorded.
+    pub fn import(&self, config: &Config) -> Result<Vec<String>> {
+        config.with_connection(|conn| self.import_on(config, conn))
+    }
+
+    /// Import applied migrations using an existing connection. See `Importer::import`
+    pub fn import_with<D: Driver>(&self, config: &Config, conn: &mut D) -> Result<Vec<String>> {
+        config.check_database_type(conn.database_type())?;
+        self.import_on(config, conn)
+    }
+
+    fn import_on(&self, config: &Config, conn: &mut dyn Driver) -> Result<Vec<String>> {
+        let table = config.migration_table()?;
+        if !conn.migration_table_exists(&table)? {
+            bail_fmt!(
+                ErrorKind::Migration,
+                "`{}` table is missing, maybe try re-setting-up? -> `setup`",
+                table
+            )
+        }
+        conn.acquire_lock(&table, IMPORT_LOCK_TIMEOUT)?;
+        let res = self.record_imported(config, conn, &table);
+        let released = conn.release_lock(&table);
+        res.and_then(|imported| released.map(|_| imported))
+    }
+
+    /// Read the source's applied migrations, in the order they were run
+    fn read_applied(&self, conn: &mut dyn Driver) -> Result<Vec<ImportedMigration>> {
+        let table = MigrationTable::parse(
+            self.table
+                .as_deref()
+                .unwrap_or_else(|| self.source.default_table()),
+        )?;
+        let query = self.source.select_query(&table, conn.database_type());
+        let rows = conn
+            .select_text_rows(&query)
+            .chain_err(|| format!("Unable to read {} migrations from `{}`", self.source, table))?;
+        let mut applied: Vec<ImportedMigration> = vec![];
+        for row in rows {
+            let mut columns = row.into_iter();
+            let version = match columns.next().flatten() {
+                Some(version) => version,
+                None => continue,
+            };
+            let name = columns.next().flatten();
+            let script = columns.next().flatten();
+            if columns.next().flatten().is_some() {
+                // undone migrations are no longer applied
+                applied.retain(|mig| mig.version != version);
+                continue;
+            }
+            applied.push(ImportedMigration {
+                version,
+                name,
+                script,
+            });
+        }
+        Ok(applied)
+    }
+
+    /// Record the source's applied migrations. Every imported migration is mapped and checked
+    /// before anything is recorded, and all records are inserted in a single transaction
+    fn record_imported(
+        &self,
+        config: &Config,
+        conn: &mut dyn Driver,
+        table: &MigrationTable,
+    ) -> Result<Vec<String>> {
+        let available = config.available_migrations()?;
+        let applied = conn.select_migrations(table)?;
+        let mut records: Vec<MigrationRecord> = vec![];
+        for mig in self.read_applied(conn)? {
+            let tag = match self.mapping {
+                Some(ref mapping) => mapping(&mig),
+                None => mig.default_tag(&available),
+            };
+            let tag = match tag {
+                Some(tag) => tag,
+                None => continue,
+            };
+            if applied.contains(&tag) || records.iter().any(|record| record.tag == tag) {
+                continue;
+            }
+            let migration = available
+                .iter()
+                .find(|available| available.tag() == tag)
+                .ok_or_else(|| {
+                    format_err!(
+                        ErrorKind::MigrationNotFound,
+                        "Tag not found: {}, imported from {} version {}",
+                        tag,
+                        self.source,
+                        mig.version
+                    )
+                })?;
+            let checksum = migration
+                .checksum(&config.database_type())
+                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+            records.push(MigrationRecord::with_tag(&tag).checksum(checksum));
+        }
+
+        match records.split_last() {
+            None => {}
+            Some((record, [])) => conn.insert_migration_tag(table, record, 0)?,
+            Some((last, rest)) => {
+                // the other records are inserted by the statement applied along with the last one
+                let db_kind = config.database_type();
+                let stmt = rest
+                    .iter()
+                    .map(|record| render_migration_tag(&db_kind, table, record, &Direction::Up))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                conn.apply_migration(table, &stmt, last, &Direction::Up, true)?;
+            }
+        }
+        Ok(records.into_iter().map(|record| record.tag).collect())
+    }
+}
+
+impl fmt::Debug for Importer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Importer")
+            .field("source", &self.source)
+            .field("table", &self.table)
+            .field("mapping", &self.mapping.is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "d-sqlite")]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EmbeddedMigration, Settings};
+    use std::env;
+    use std::fs;
+
+    fn sqlx_config(name: &str) -> Config {
+        let dir = env::temp_dir().join(format!("migrant_lib_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Settings::configure_sqlite()
+            .database_path(dir.join("db.db"))
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut config = Config::with_settings(&settings);
+        config.setup().unwrap();
+        config
+            .use_migrations(&[
+                EmbeddedMigration::with_tag("20240101000000_first")
+                    .up("select 1;")
+                    .boxed(),
+                EmbeddedMigration::with_tag("20240102000000_second")
+                    .up("select 1;")
+                    .boxed(),
+                EmbeddedMigration::with_tag("20240103000000_third")
+                    .up("select 1;")
+                    .boxed(),
+            ])
+            .unwrap();
+        let conn = rusqlite::Connection::open(config.database_path().unwrap()).unwrap();
+        conn.execute_batch(
+            "create table _sqlx_migrations (version bigint, description text, success boolean);
+             insert into _sqlx_migrations values
+                 (20240101000000, 'first', true), (20240102000000, 'second', true),
+                 (20240103000000, 'third', true), (20240104000000, 'fourth', false);",
+        )
+        .unwrap();
+        config
+    }
+
+    fn applied(config: &Config) -> Vec<String> {
+        config
+            .with_connection(|conn| conn.select_migrations(&config.migration_table()?))
+            .unwrap()
+    }
+
+    #[test]
+    fn import_sqlx() {
+        let config = sqlx_config("import_sqlx");
+        let imported = Importer::with_source(ImportSource::Sqlx)
+            .import(&config)
+            .unwrap();
+        let expected = vec![
+            "20240101000000_first",
+            "20240102000000_second",
+            "20240103000000_third",
+        ];
+        assert_eq!(expected, imported, "Assert successful migrations imported");
+        assert_eq!(
+            expected,
+            applied(&config),
+            "Assert imported migrations recorded"
+        );
+
+        let imported = Importer::with_source(ImportSource::Sqlx)
+            .import(&config)
+            .unwrap();
+        assert!(imported.is_empty(), "Assert applied migrations are skipped");
+    }
+
+    #[test]
+    fn import_unknown_tag_records_nothing() {
+        let config = sqlx_config("import_unknown_tag");
+        let err = Importer::with_source(ImportSource::Sqlx)
+            .map(|mig| match mig.version.as_str() {
+                "20240103000000" => Some("20240103000000_missing".into()),
+                version => Some(format!("{}_{}", version, mig.name.as_ref()?)),
+            })
+            .import(&config)
+            .expect_err("Assert unknown tag errors");
+        assert!(
+            matches!(*err.kind(), ErrorKind::MigrationNotFound(_)),
+            "Assert migration not found error"
+        );
+        assert!(applied(&config).is_empty(), "Assert nothing recorded");
+    }
+}
@@ -0,0 +1,418 @@
+/*!
+Migration directory layouts of other migration tools
+
+*/
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::errors::*;
+use crate::migration::{FileMigration, RepeatableMigration};
+
+/// Directory layout of the file migrations under `migration_location`
+///
+/// Set with the `migration_format` settings builder methods or `Migrant.toml` key
+/// so existing migrations of other tools can be used without renaming them.
+/// Tags of migrations found in other layouts are their file names without extensions.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum MigrationFormat {
+    /// `<timestamp>_<tag>` directories containing `up.sql` and `down.sql`, or single
+    /// `<timestamp>_<tag>.sql` files with `-- migrate:up` and `-- migrate:down` sections,
+    /// and `repeatable/<tag>.sql` repeatable migrations. The default
+    #[default]
+    Migrant,
+    /// golang-migrate `<version>_<name>.up.sql` and `<version>_<name>.down.sql` files
+    GolangMigrate,
+    /// Flyway `V<version>__<description>.sql` files, with optional
+    /// `U<version>__<description>.sql` undo files used as `down` migrations
+    /// and `R__<description>.sql` repeatable migrations
+    Flyway,
+    /// dbmate `<version>_<name>.sql` files with `-- migrate:up` and `-- migrate:down` sections
+    Dbmate,
+}
+impl std::str::FromStr for MigrationFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "migrant" => MigrationFormat::Migrant,
+            "golang-migrate" => MigrationFormat::GolangMigrate,
+            "flyway" => MigrationFormat::Flyway,
+            "dbmate" => MigrationFormat::Dbmate,
+            _ => bail_fmt!(
+                ErrorKind::Config,
+                "Invalid migration format: `{}`. Expected one of \
+                 `migrant`, `golang-migrate`, `flyway` or `dbmate`",
+                s
+            ),
+        })
+    }
+}
+impl fmt::Display for MigrationFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationFormat::Migrant => write!(f, "migrant"),
+            MigrationFormat::GolangMigrate => write!(f, "golang-migrate"),
+            MigrationFormat::Flyway => write!(f, "flyway"),
+            MigrationFormat::Dbmate => write!(f, "dbmate"),
+        }
+    }
+}
+
+/// Collect the `.sql` files under `mig_root` with their file names
+fn sql_files(mig_root: &Path) -> Vec<(String, PathBuf)> {
+    WalkDir::new(mig_root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_owned();
+            if name.ends_with(".sql") {
+                Some((name, entry.path().to_path_buf()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The tag and files of a migration version
+#[derive(Default)]
+struct VersionFiles {
+    tag: Option<String>,
+    up: Option<PathBuf>,
+    down: Option<PathBuf>,
+}
+
+/// Create the migrations of each version in order. Each version requires an `up` file
+fn version_migrations<K: Ord>(found: BTreeMap<K, VersionFiles>) -> Result<Vec<FileMigration>> {
+    found
+        .into_values()
+        .map(|files| match (files.tag, files.up) {
+            (Some(tag), Some(up)) => Ok(file_migration(&tag, up, files.down, false)),
+            _ => bail_fmt!(
+                ErrorKind::MigrationNotFound,
+                "Up migration not found for: {:?}",
+                files.down.unwrap_or_default()
+            ),
+        })
+        .collect()
+}
+
+fn file_migration(
+    tag: &str,
+    up: PathBuf,
+    down: Option<PathBuf>,
+    single_file: bool,
+) -> FileMigration {
+    let mut mig = FileMigration::with_tag(tag);
+    mig.up = Some(up);
+    mig.down = down;
+    mig.single_file = single_file;
+    mig
+}
+
+fn duplicate_version(version: &str, path: &Path) -> Error {
+    format_err!(
+        ErrorKind::TagError,
+        "Duplicate migration version `{}` in: {:?}",
+        version,
+        path
+    )
+    .into()
+}
+
+/// Parse a numeric migration version
+fn parse_version_number(version: &str, name: &str) -> Result<u64> {
+    version.parse().map_err(|_| {
+        format_err!(
+            ErrorKind::TagError,
+            "Invalid migration version `{}` in: {:?}",
+            version,
+            name
+        )
+        .into()
+    })
+}
+
+/// Search for golang-migrate `<version>_<name>.up.sql` & `.down.sql` files.
+/// Files not following this naming are ignored, as with golang-migrate
+pub(crate) fn search_golang_migrate(mig_root: &Path) -> Result<Vec<FileMigration>> {
+    let mut found: BTreeMap<u64, VersionFiles> = BTreeMap::new();
+    for (name, path) in sql_files(mig_root) {
+        let (stem, is_up) = if let Some(stem) = name.strip_suffix(".up.sql") {
+            (stem, true)
+        } else if let Some(stem) = name.strip_suffix(".down.sql") {
+            (stem, false)
+        } else {
+            continue;
+        };
+        let version = match stem.find('_') {
+            Some(ind) if ind > 0 && stem[..ind].chars().all(|c| c.is_ascii_digit()) => &stem[..ind],
+            _ => continue,
+        };
+        let number = parse_version_number(version, &name)?;
+        let files = found.entry(number).or_default();
+        let tag = files.tag.get_or_insert_with(|| stem.to_owned());
+        let file = if is_up {
+            &mut files.up
+        } else {
+            &mut files.down
+        };
+        if tag != stem || file.is_some() {
+            return Err(duplicate_version(version, &path));
+        }
+        *file = Some(path);
+    }
+    version_migrations(found)
+}
+
+/// Parse a Flyway version, e.g. `1`, `1.2` or `1_2`, ignoring trailing zero parts
+/// so `1` and `1.0` are the same version
+fn parse_flyway_version(version: &str, name: &str) -> Result<Vec<u64>> {
+    let mut parts = version
+        .split(['.', '_'])
+        .map(|part| parse_version_number(part, name))
+        .collect::<Result<Vec<_>>>()?;
+    while parts.len() > 1 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Ok(parts)
+}
+
+/// Search for Flyway `V<version>__<description>.sql` and `U<version>__<description>.sql` files.
+/// Files without a `V` or `U` prefix are ignored, repeatable `R__` files are found
+/// by `search_flyway_repeatable`
+pub(crate) fn search_flyway(mig_root: &Path) -> Result<Vec<FileMigration>> {
+    let mut found: BTreeMap<Vec<u64>, VersionFiles> = BTreeMap::new();
+    for (name, path) in sql_files(mig_root) {
+        let stem = name.trim_end_matches(".sql");
+        let is_undo = match stem.chars().next() {
+            Some('V') => false,
+            Some('U') => true,
+            _ => continue,
+        };
+        let version = match stem[1..].find("__") {
+            Some(ind) if ind > 0 => &stem[1..=ind],
+            _ => continue,
+        };
+        let key = parse_flyway_version(version, &name)?;
+        let files = found.entry(key).or_default();
+        let file = if is_undo {
+            &mut files.down
+        } else {
+            &mut files.up
+        };
+        if file.is_some() {
+            return Err(duplicate_version(version, &path));
+        }
+        *file = Some(path);
+        if !is_undo {
+            files.tag = Some(stem.to_owned());
+        }
+    }
+    version_migrations(found)
+}
+
+/// Search for Flyway repeatable `R__<description>.sql` files, ordered by description
+/// as Flyway applies them
+pub(crate) fn search_flyway_repeatable(mig_root: &Path) -> Result<Vec<RepeatableMigration>> {
+    let mut found = sql_files(mig_root)
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("R__"))
+        .map(|(name, path)| {
+            let mut mig = RepeatableMigration::with_tag(name.trim_end_matches(".sql"));
+            mig.file = Some(path);
+            mig
+        })
+        .collect::<Vec<_>>();
+    found.sort_by(|a, b| a.tag.cmp(&b.tag));
+    Ok(found)
+}
+
+/// Search for dbmate `<version>_<name>.sql` files.
+/// Files not starting with a version are ignored, as with dbmate
+pub(crate) fn search_dbmate(mig_root: &Path) -> Result<Vec<FileMigration>> {
+    let mut found: BTreeMap<u64, (String, PathBuf)> = BTreeMap::new();
+    for (name, path) in sql_files(mig_root) {
+        let stem = name.trim_end_matches(".sql");
+        let end = stem
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(stem.len());
+        if end == 0 {
+            continue;
+        }
+        let version = &stem[..end];
+        let number = parse_version_number(version, &name)?;
+        if found.contains_key(&number) {
+            return Err(duplicate_version(version, &path));
+        }
+        found.insert(number, (stem.to_owned(), path));
+    }
+    Ok(found
+        .into_values()
+        .map(|(tag, path)| file_migration(&tag, path.clone(), Some(path), true))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::migratable::Migratable;
+    use crate::test::fixture_dir;
+    use crate::{DbKind, Direction};
+
+    fn tags<T: Migratable>(migrations: &[T]) -> Vec<String> {
+        migrations.iter().map(|mig| mig.tag()).collect()
+    }
+
+    fn is_tag_error<T: fmt::Debug>(res: Result<T>) -> bool {
+        matches!(res, Err(ref e) if matches!(*e.kind(), ErrorKind::TagError(_)))
+    }
+
+    #[test]
+    fn golang_migrate_layout() {
+        let dir = fixture_dir(
+            "golang_migrate_layout",
+            &[
+                ("1_create_users.up.sql", "create table users(id int);"),
+                ("1_create_users.down.sql", "drop table users;"),
+                ("2_add_email.up.sql", "alter table users add email text;"),
+                ("nested/10_later.up.sql", "select 1;"),
+                ("schema.sql", "select 1;"),
+                ("README.md", "docs"),
+            ],
+        );
+        let found = search_golang_migrate(&dir).unwrap();
+        assert_eq!(
+            vec!["1_create_users", "2_add_email", "10_later"],
+            tags(&found),
+            "Assert migrations ordered by version"
+        );
+        assert_eq!(
+            Some(dir.join("1_create_users.down.sql")),
+            found[0].down,
+            "Assert down file found"
+        );
+        assert_eq!(None, found[1].down, "Assert down file is optional");
+
+        let dir = fixture_dir(
+            "golang_migrate_duplicate",
+            &[("1_first.up.sql", ""), ("1_other.up.sql", "")],
+        );
+        assert!(
+            is_tag_error(search_golang_migrate(&dir)),
+            "Assert duplicate version errors"
+        );
+
+        let dir = fixture_dir("golang_migrate_no_up", &[("1_first.down.sql", "")]);
+        assert!(
+            search_golang_migrate(&dir).is_err(),
+            "Assert down file without an up file errors"
+        );
+    }
+
+    #[test]
+    fn flyway_layout() {
+        let dir = fixture_dir(
+            "flyway_layout",
+            &[
+                ("V1__init.sql", "create table users(id int);"),
+                ("U1__init.sql", "drop table users;"),
+                ("V1.1__add_email.sql", "alter table users add email text;"),
+                ("V2__more.sql", "select 1;"),
+                ("R__views.sql", "create view v as select 1;"),
+                ("R__a_functions.sql", "select 1;"),
+                ("afterMigrate.sql", "select 1;"),
+            ],
+        );
+        let found = search_flyway(&dir).unwrap();
+        assert_eq!(
+            vec!["V1__init", "V1.1__add_email", "V2__more"],
+            tags(&found),
+            "Assert versioned migrations ordered by version"
+        );
+        assert_eq!(
+            Some(dir.join("U1__init.sql")),
+            found[0].down,
+            "Assert undo file is the down migration"
+        );
+
+        let repeatable = search_flyway_repeatable(&dir).unwrap();
+        assert_eq!(
+            vec!["R__a_functions", "R__views"],
+            tags(&repeatable),
+            "Assert repeatable migrations ordered by description"
+        );
+        assert_eq!(
+            Some(dir.join("R__views.sql")),
+            repeatable[1].file,
+            "Assert repeatable migration file"
+        );
+
+        let dir = fixture_dir(
+            "flyway_duplicate",
+            &[("V1__init.sql", ""), ("V1.0__other.sql", "")],
+        );
+        assert!(
+            is_tag_error(search_flyway(&dir)),
+            "Assert equivalent versions are duplicates"
+        );
+    }
+
+    #[test]
+    fn dbmate_layout() {
+        let dir = fixture_dir(
+            "dbmate_layout",
+            &[
+                (
+                    "20240101000000_create_users.sql",
+                    "-- migrate:up\ncreate table users(id int);\n\n\
+                     -- migrate:down\ndrop table users;\n",
+                ),
+                (
+                    "20240102000000_add_email.sql",
+                    "-- migrate:up\nalter table users add email text;\n",
+                ),
+                ("schema.sql", "create table users(id int);"),
+            ],
+        );
+        let found = search_dbmate(&dir).unwrap();
+        assert_eq!(
+            vec!["20240101000000_create_users", "20240102000000_add_email"],
+            tags(&found),
+            "Assert migrations ordered by version"
+        );
+
+        let statements = |mig: &FileMigration, direction: Direction| {
+            mig.statements(&DbKind::Sqlite, &direction)
+                .unwrap()
+                .map(|stmt| stmt.trim().to_string())
+        };
+        assert_eq!(
+            Some("create table users(id int);".to_string()),
+            statements(&found[0], Direction::Up),
+            "Assert up section of the combined file"
+        );
+        assert_eq!(
+            Some("drop table users;".to_string()),
+            statements(&found[0], Direction::Down),
+            "Assert down section of the combined file"
+        );
+        assert_eq!(
+            None,
+            statements(&found[1], Direction::Down),
+            "Assert down section is optional"
+        );
+
+        let dir = fixture_dir(
+            "dbmate_duplicate",
+            &[("001_first.sql", ""), ("1_other.sql", "")],
+        );
+        assert!(
+            is_tag_error(search_dbmate(&dir)),
+            "Assert duplicate version errors"
+        );
+    }
+}
@@ -16,6 +16,24 @@ macro_rules! print_flush {
     }
 }
 
+/// Embed the migrations found under a directory, relative to the crate root, by
+/// `embed::generate` in the crate's build script. Expands to a `Vec<Box<dyn Migratable>>`
+/// of `EmbeddedMigration`s with cli compatible tags, in order, followed by any
+/// `RepeatableMigration`s.
+///
+/// The directory must be given as the same string literal passed to `embed::generate`.
+#[macro_export]
+macro_rules! embed_migrations {
+    ($dir:literal) => {
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/migrant_embedded/",
+            $dir,
+            "/migrations.rs"
+        ))
+    };
+}
+
 // -------------
 // error-chain
 // -------------
@@ -1,4 +1,4 @@
-use crate::{Config, DbKind, Direction};
+use crate::{Config, DbKind, Direction, Driver, MigrationRecord, MigrationTable};
 use std::fmt;
 
 pub trait MigratableClone {
@@ -14,19 +14,100 @@ where
 }
 
 /// A type that can be used to define database migrations
-pub trait Migratable: MigratableClone {
+///
+/// Migrations are `Send + Sync` so a `Config` and the futures of an async
+/// `Migrator` can be moved between threads.
+#[cfg_attr(feature = "async", async_trait::async_trait)]
+pub trait Migratable: MigratableClone + Send + Sync {
     /// Define functionality that runs for `up` migrations
-    fn apply_up(&self, _: DbKind, _: &Config) -> Result<(), Box<dyn (::std::error::Error)>> {
+    fn apply_up(&self, _: DbKind, _: &Config) -> Result<(), Box<dyn ::std::error::Error>> {
         print_flush!("(empty)");
         Ok(())
     }
 
     /// Define functionality that runs for `down` migrations
-    fn apply_down(&self, _: DbKind, _: &Config) -> Result<(), Box<dyn (::std::error::Error)>> {
+    fn apply_down(&self, _: DbKind, _: &Config) -> Result<(), Box<dyn ::std::error::Error>> {
         print_flush!("(empty)");
         Ok(())
     }
 
+    /// Define functionality that runs for `up` migrations when applied by an async `Migrator`.
+    ///
+    /// Defaults to calling `apply_up` on tokio's blocking threads.
+    #[cfg(feature = "async")]
+    async fn apply_up_async(
+        &self,
+        db_kind: DbKind,
+        config: &Config,
+    ) -> Result<(), Box<dyn ::std::error::Error>> {
+        apply_blocking(self.clone_migratable_box(), db_kind, config, Direction::Up).await
+    }
+
+    /// Define functionality that runs for `down` migrations when applied by an async `Migrator`.
+    ///
+    /// Defaults to calling `apply_down` on tokio's blocking threads.
+    #[cfg(feature = "async")]
+    async fn apply_down_async(
+        &self,
+        db_kind: DbKind,
+        config: &Config,
+    ) -> Result<(), Box<dyn ::std::error::Error>> {
+        apply_blocking(
+            self.clone_migratable_box(),
+            db_kind,
+            config,
+            Direction::Down,
+        )
+        .await
+    }
+
+    /// Run this migration on the `Migrator`'s connection, updating the migration `table`
+    /// with `record` in the same transaction. Returns `false` if the migration should
+    /// instead be run with `apply_up`/`apply_down`. Defaults to `false`
+    fn apply_on(
+        &self,
+        _: &mut dyn Driver,
+        _: &MigrationTable,
+        _: &MigrationRecord,
+        _: &Direction,
+    ) -> Result<bool, Box<dyn ::std::error::Error>> {
+        Ok(false)
+    }
+
+    /// SQL statements to run for the given direction on a `DbKind` database,
+    /// if this migration is defined by SQL.
+    ///
+    /// When statements are returned, the `Migrator` runs them on the same connection as
+    /// the migration table update instead of calling `apply_up`/`apply_down`.
+    fn statements(
+        &self,
+        _: &DbKind,
+        _: &Direction,
+    ) -> Result<Option<String>, Box<dyn ::std::error::Error>> {
+        Ok(None)
+    }
+
+    /// Whether `statements` should be committed in a single transaction along with
+    /// the migration table update. Defaults to `true`
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    /// Checksum of the migration's `up` content for a `DbKind` database, recorded in the
+    /// migration table when applied. Defaults to a SHA-256 of `statements(db_kind, &Direction::Up)`,
+    /// or `None` for migrations that aren't defined by SQL.
+    fn checksum(&self, db_kind: &DbKind) -> Result<Option<String>, Box<dyn ::std::error::Error>> {
+        Ok(self
+            .statements(db_kind, &Direction::Up)?
+            .map(|stmt| crate::migration::checksum(&stmt)))
+    }
+
+    /// Whether this migration is re-applied whenever its `checksum` changes instead of
+    /// being applied once. Defaults to `false`, see `RepeatableMigration`
+    fn is_repeatable(&self) -> bool {
+        false
+    }
+
     /// A unique identifying tag
     fn tag(&self) -> String;
 
@@ -35,6 +116,27 @@ pub trait Migratable: MigratableClone {
         self.tag()
     }
 }
+/// Run `apply_up` or `apply_down` of `migration` on a blocking thread
+#[cfg(feature = "async")]
+async fn apply_blocking(
+    migration: Box<dyn Migratable>,
+    db_kind: DbKind,
+    config: &Config,
+    direction: Direction,
+) -> Result<(), Box<dyn ::std::error::Error>> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        match direction {
+            Direction::Up => migration.apply_up(db_kind, &config),
+            Direction::Down => migration.apply_down(db_kind, &config),
+        }
+        // errors aren't required to be `Send`, so only their message leaves the thread
+        .map_err(|e| e.to_string())
+    })
+    .await?
+    .map_err(|e| e.into())
+}
+
 impl Clone for Box<dyn Migratable> {
     fn clone(&self) -> Box<dyn Migratable> {
         self.clone_migratable_box()
@@ -3,9 +3,12 @@ Embedded / programmable migrations
 
 */
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std;
 use std::borrow::Cow;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::config::Config;
 #[cfg(not(any(feature = "d-postgres", feature = "d-sqlite", feature = "d-mysql")))]
@@ -14,7 +17,137 @@ use crate::connection::ConnConfig;
 use crate::drivers;
 use crate::errors::*;
 use crate::migratable::Migratable;
-use crate::{DbKind, Direction, DT_FORMAT};
+use crate::{DbKind, Direction, Driver, MigrationRecord, MigrationTable, DT_FORMAT};
+
+static NO_TRANSACTION_MARKER: &str = "-- migrant:no-transaction";
+
+/// Returns true if the statements contain a line opting out of transactions
+pub(crate) fn has_no_transaction_marker(stmt: &str) -> bool {
+    stmt.lines()
+        .any(|line| line.trim() == NO_TRANSACTION_MARKER)
+}
+
+static UP_SECTION_MARKER: &str = "-- migrate:up";
+
+/// Parse a `-- migrate:up` or `-- migrate:down` section marker line, returning
+/// the section's direction and whether it runs in a transaction
+fn parse_section_marker(line: &str) -> Result<Option<(Direction, bool)>> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("--") {
+        return Ok(None);
+    }
+    let direction = match words.next() {
+        Some("migrate:up") => Direction::Up,
+        Some("migrate:down") => Direction::Down,
+        _ => return Ok(None),
+    };
+    let mut transactional = true;
+    for option in words {
+        match option {
+            "transaction:true" => transactional = true,
+            "transaction:false" => transactional = false,
+            _ => bail_fmt!(
+                ErrorKind::Migration,
+                "Unsupported migration section option `{}` in: {:?}",
+                option,
+                line.trim()
+            ),
+        }
+    }
+    Ok(Some((direction, transactional)))
+}
+
+/// Split the contents of a single-file migration into the statements of its
+/// `-- migrate:up` and `-- migrate:down` sections.
+///
+/// An up section is required and only comments may come before the first section.
+/// Empty sections are `None`. A section opted out of transactions with
+/// `transaction:false` is given a `-- migrant:no-transaction` line.
+pub(crate) fn split_sections(contents: &str) -> Result<(Option<String>, Option<String>)> {
+    let mut sections: Vec<(Direction, bool, Vec<&str>)> = vec![];
+    for line in contents.lines() {
+        if let Some((direction, transactional)) = parse_section_marker(line)? {
+            if sections.iter().any(|section| section.0 == direction) {
+                bail_fmt!(ErrorKind::Migration, "Duplicate `{}` section", line.trim())
+            }
+            sections.push((direction, transactional, vec![]));
+            continue;
+        }
+        match sections.last_mut() {
+            Some(section) => section.2.push(line),
+            None if line.trim().is_empty() || line.trim_start().starts_with("--") => (),
+            None => bail_fmt!(
+                ErrorKind::Migration,
+                "Statements found before the first `-- migrate:up` or `-- migrate:down` section"
+            ),
+        }
+    }
+
+    let mut up = None;
+    let mut down = None;
+    for (direction, transactional, lines) in sections {
+        let body = lines.join("\n");
+        let body = body.trim();
+        if body.is_empty() {
+            continue;
+        }
+        let stmt = if transactional {
+            format!("{}\n", body)
+        } else {
+            format!("{}\n{}\n", NO_TRANSACTION_MARKER, body)
+        };
+        match direction {
+            Direction::Up => up = Some(stmt),
+            Direction::Down => down = Some(stmt),
+        }
+    }
+    if up.is_none() {
+        bail_fmt!(
+            ErrorKind::Migration,
+            "Missing or empty `{}` section",
+            UP_SECTION_MARKER
+        )
+    }
+    Ok((up, down))
+}
+
+/// Return the hex encoded SHA-256 checksum of migration content
+pub(crate) fn checksum(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Set the `db_kind` variant of a migration, replacing any existing one
+fn set_variant<T>(variants: &mut Vec<(DbKind, T)>, db_kind: DbKind, variant: T) {
+    variants.retain(|(kind, _)| *kind != db_kind);
+    variants.push((db_kind, variant));
+}
+
+/// Return the `db_kind` variant of a migration, or `default` if there isn't one.
+/// Returns an `ErrorKind::MigrationNotFound` if there's neither but other variants exist
+fn variant_or<'a, T>(
+    variants: &'a [(DbKind, T)],
+    db_kind: &DbKind,
+    default: Option<&'a T>,
+    direction: &Direction,
+    tag: &str,
+) -> Result<Option<&'a T>> {
+    if let Some((_, variant)) = variants.iter().find(|(kind, _)| kind == db_kind) {
+        return Ok(Some(variant));
+    }
+    if default.is_none() && !variants.is_empty() {
+        bail_fmt!(
+            ErrorKind::MigrationNotFound,
+            "No `{}` migration for {} databases found for tag: {}",
+            direction,
+            db_kind,
+            tag
+        )
+    }
+    Ok(default)
+}
 
 /// Define a migration that uses SQL statements saved in files.
 ///
@@ -22,15 +155,28 @@ use crate::{DbKind, Direction, DT_FORMAT};
 /// File paths can be absolute or relative. Relative file paths are relative
 /// to the directory from which the program is run.
 ///
-/// *Note:* SQL statements are batch executed as is. If you want your migration
-/// to happen atomically in a transaction you should manually wrap your statements
-/// in a transaction (`begin transaction; ... commit;`).
+/// *Note:* SQL statements are batch executed in a transaction along with the update
+/// to the migrations table. Statements that can't be run in a transaction
+/// (e.g. `create index concurrently`) can opt out using `use_transaction(false)`
+/// or by including a `-- migrant:no-transaction` line.
+///
+/// A single file with `-- migrate:up` and `-- migrate:down` sections can be
+/// used instead of separate files, see `FileMigration::single_file`.
+///
+/// Files for a specific database type can be used in place of the `up` and `down`
+/// files, see `FileMigration::up_for`. Migration directories may contain
+/// `up.<database>.sql` and `down.<database>.sql` files, e.g. `up.postgres.sql`,
+/// which are used instead of `up.sql` and `down.sql` on that type of database.
 #[derive(Clone, Debug)]
 pub struct FileMigration {
     pub tag: String,
     pub up: Option<PathBuf>,
     pub down: Option<PathBuf>,
+    pub(crate) up_for: Vec<(DbKind, PathBuf)>,
+    pub(crate) down_for: Vec<(DbKind, PathBuf)>,
     pub(crate) stamp: Option<DateTime<Utc>>,
+    pub(crate) transactional: bool,
+    pub(crate) single_file: bool,
 }
 impl FileMigration {
     /// Create a new `FileMigration` with a given tag
@@ -39,7 +185,11 @@ impl FileMigration {
             tag: tag.to_owned(),
             up: None,
             down: None,
+            up_for: vec![],
+            down_for: vec![],
             stamp: None,
+            transactional: true,
+            single_file: false,
         }
     }
 
@@ -78,6 +228,104 @@ impl FileMigration {
         Ok(self)
     }
 
+    /// Define the file to use for running `up` migrations on a `db_kind` database,
+    /// in place of the `up` file. See `FileMigration::up`.
+    pub fn up_for<T: AsRef<Path>>(&mut self, db_kind: DbKind, up_file: T) -> Result<&mut Self> {
+        let path = up_file.as_ref();
+        Self::check_path(path)?;
+        set_variant(&mut self.up_for, db_kind, path.to_owned());
+        Ok(self)
+    }
+
+    /// Define the file to use for running `down` migrations on a `db_kind` database,
+    /// in place of the `down` file. See `FileMigration::down`.
+    pub fn down_for<T: AsRef<Path>>(&mut self, db_kind: DbKind, down_file: T) -> Result<&mut Self> {
+        let path = down_file.as_ref();
+        Self::check_path(path)?;
+        set_variant(&mut self.down_for, db_kind, path.to_owned());
+        Ok(self)
+    }
+
+    /// Return the file to use for running migrations in the given `direction` on a
+    /// `db_kind` database, falling back to the `up` or `down` file
+    pub(crate) fn path_for(
+        &self,
+        db_kind: &DbKind,
+        direction: &Direction,
+    ) -> Result<Option<&PathBuf>> {
+        let (path, variants) = match *direction {
+            Direction::Up => (self.up.as_ref(), &self.up_for),
+            Direction::Down => (self.down.as_ref(), &self.down_for),
+        };
+        variant_or(variants, db_kind, path, direction, &self.tag)
+    }
+
+    /// Define a single file with `-- migrate:up` and `-- migrate:down` sections
+    /// to use for running both `up` and `down` migrations.
+    ///
+    /// The up section is required and the down section may be left out. A section can
+    /// opt out of transactions with `-- migrate:up transaction:false`.
+    ///
+    /// ```sql
+    /// -- migrate:up
+    /// create table users (id integer primary key, name text);
+    ///
+    /// -- migrate:down
+    /// drop table users;
+    /// ```
+    ///
+    /// *Note:* The file must be present at run-time and is only parsed when the migration runs.
+    pub fn single_file<T: AsRef<Path>>(&mut self, file: T) -> Result<&mut Self> {
+        let path = file.as_ref();
+        Self::check_path(path)?;
+        self.up = Some(path.to_owned());
+        self.down = Some(path.to_owned());
+        self.single_file = true;
+        Ok(self)
+    }
+
+    /// Run the section of a single-file migration for `direction` on a new connection
+    fn apply_section(
+        &self,
+        direction: &Direction,
+        db_kind: DbKind,
+        config: &Config,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let stmt = match self.statements(&db_kind, direction)? {
+            Some(stmt) => stmt,
+            None => {
+                print_flush!("(empty) ...");
+                return Ok(());
+            }
+        };
+        match db_kind {
+            DbKind::Sqlite => {
+                let db_path = config.database_path()?;
+                drivers::sqlite::run_migration_str(&db_path, &stmt)?;
+            }
+            DbKind::Postgres => {
+                let conn_str = config.connect_string()?;
+                drivers::pg::run_migration_str(
+                    config.ssl_cert_file().as_deref(),
+                    &conn_str,
+                    &stmt,
+                )?;
+            }
+            DbKind::MySql => {
+                let conn_str = config.connect_string()?;
+                drivers::mysql::run_migration_str(&conn_str, &stmt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Toggle running statements in a transaction with the migration table update.
+    /// Defaults to `true`.
+    pub fn use_transaction(&mut self, transactional: bool) -> &mut Self {
+        self.transactional = transactional;
+        self
+    }
+
     /// Box this migration up so it can be stored with other migrations
     pub fn boxed(&self) -> Box<dyn Migratable> {
         Box::new(self.clone())
@@ -90,7 +338,10 @@ impl Migratable for FileMigration {
         db_kind: DbKind,
         config: &Config,
     ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        if let Some(ref up) = self.up {
+        if self.single_file {
+            return self.apply_section(&Direction::Up, db_kind, config);
+        }
+        if let Some(up) = self.path_for(&db_kind, &Direction::Up)? {
             match db_kind {
                 DbKind::Sqlite => {
                     let db_path = config.database_path()?;
@@ -115,7 +366,10 @@ impl Migratable for FileMigration {
         db_kind: DbKind,
         config: &Config,
     ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        if let Some(ref down) = self.down {
+        if self.single_file {
+            return self.apply_section(&Direction::Down, db_kind, config);
+        }
+        if let Some(down) = self.path_for(&db_kind, &Direction::Down)? {
             match db_kind {
                 DbKind::Sqlite => {
                     let db_path = config.database_path()?;
@@ -135,6 +389,32 @@ impl Migratable for FileMigration {
         }
         Ok(())
     }
+    fn statements(
+        &self,
+        db_kind: &DbKind,
+        direction: &Direction,
+    ) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+        let path = self.path_for(db_kind, direction)?;
+        if self.single_file {
+            let path = match path {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            let (up, down) = split_sections(&fs::read_to_string(path)?)
+                .chain_err(|| format!("Invalid migration file: {:?}", path))?;
+            return Ok(match *direction {
+                Direction::Up => up,
+                Direction::Down => down,
+            });
+        }
+        Ok(match path {
+            Some(path) => Some(fs::read_to_string(path)?),
+            None => None,
+        })
+    }
+    fn is_transactional(&self) -> bool {
+        self.transactional
+    }
     fn tag(&self) -> String {
         match self.stamp.as_ref() {
             Some(dt) => {
@@ -145,17 +425,14 @@ impl Migratable for FileMigration {
         }
     }
     fn description(&self, direction: &Direction) -> String {
-        match *direction {
-            Direction::Up => self
-                .up
-                .as_ref()
-                .map(|p| format!("{:?}", p))
-                .unwrap_or_else(|| self.tag()),
-            Direction::Down => self
-                .down
-                .as_ref()
-                .map(|p| format!("{:?}", p))
-                .unwrap_or_else(|| self.tag()),
+        let (path, variants) = match *direction {
+            Direction::Up => (self.up.as_ref(), &self.up_for),
+            Direction::Down => (self.down.as_ref(), &self.down_for),
+        };
+        // the file that's run depends on the database type when there are variants
+        match path {
+            Some(path) if variants.is_empty() => format!("{:?}", path),
+            _ => self.tag(),
         }
     }
 }
@@ -167,9 +444,10 @@ impl Migratable for FileMigration {
 /// standard [`include_str!`](https://doc.rust-lang.org/std/macro.include_str.html) macro
 /// can be used to embed contents of files, or a string literal can be provided.
 ///
-/// *Note:* SQL statements are batch executed as is. If you want your migration
-/// to happen atomically in a transaction you should manually wrap your statements
-/// in a transaction (`begin transaction; ... commit;`).
+/// *Note:* SQL statements are batch executed in a transaction along with the update
+/// to the migrations table. Statements that can't be run in a transaction
+/// (e.g. `create index concurrently`) can opt out using `use_transaction(false)`
+/// or by including a `-- migrant:no-transaction` line.
 ///
 /// Database specific features (`d-postgres`/`d-sqlite`/`d-mysql`) are required to use
 /// this functionality.
@@ -206,6 +484,9 @@ pub struct EmbeddedMigration {
     pub tag: String,
     pub up: Option<Cow<'static, str>>,
     pub down: Option<Cow<'static, str>>,
+    pub(crate) up_for: Vec<(DbKind, Cow<'static, str>)>,
+    pub(crate) down_for: Vec<(DbKind, Cow<'static, str>)>,
+    pub(crate) transactional: bool,
 }
 impl EmbeddedMigration {
     /// Create a new `EmbeddedMigration` with the given tag
@@ -221,6 +502,9 @@ impl EmbeddedMigration {
             tag: tag.to_owned(),
             up: None,
             down: None,
+            up_for: vec![],
+            down_for: vec![],
+            transactional: true,
         }
     }
 
@@ -236,6 +520,78 @@ impl EmbeddedMigration {
         self
     }
 
+    /// `&'static str` or `String` of statements to use for `up` migrations
+    /// on a `db_kind` database, in place of the `up` statements.
+    ///
+    /// ```rust,no_run
+    /// # extern crate migrant_lib;
+    /// # use migrant_lib::{DbKind, EmbeddedMigration};
+    /// # fn main() { run().unwrap(); }
+    /// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// # #[cfg(any(feature="d-sqlite", feature="d-postgres", feature="d-mysql"))]
+    /// EmbeddedMigration::with_tag("create-places-table")
+    ///     .up("create table places(id integer primary key);")
+    ///     .up_for(DbKind::Postgres, "create table places(id bigserial primary key);")
+    ///     .down("drop table places;");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn up_for<T: Into<Cow<'static, str>>>(&mut self, db_kind: DbKind, stmt: T) -> &mut Self {
+        set_variant(&mut self.up_for, db_kind, stmt.into());
+        self
+    }
+
+    /// `&'static str` or `String` of statements to use for `down` migrations
+    /// on a `db_kind` database, in place of the `down` statements
+    pub fn down_for<T: Into<Cow<'static, str>>>(&mut self, db_kind: DbKind, stmt: T) -> &mut Self {
+        set_variant(&mut self.down_for, db_kind, stmt.into());
+        self
+    }
+
+    /// Use the `-- migrate:up` and `-- migrate:down` sections of a single-file
+    /// migration as the `up` and `down` statements. See `FileMigration::single_file`.
+    ///
+    /// Returns an `ErrorKind::Migration` if the sections can't be parsed.
+    ///
+    /// ```rust,no_run
+    /// # extern crate migrant_lib;
+    /// # use migrant_lib::EmbeddedMigration;
+    /// # fn main() { run().unwrap(); }
+    /// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// # #[cfg(any(feature="d-sqlite", feature="d-postgres", feature="d-mysql"))]
+    /// EmbeddedMigration::with_tag("create-places-table")
+    ///     .from_str("-- migrate:up\ncreate table places(id integer);\n-- migrate:down\ndrop table places;")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_str(&mut self, contents: &str) -> Result<&mut Self> {
+        let (up, down) = split_sections(contents)?;
+        self.up = up.map(Cow::Owned);
+        self.down = down.map(Cow::Owned);
+        Ok(self)
+    }
+
+    /// Return the statements to run in the given `direction` on a `db_kind` database,
+    /// falling back to the `up` or `down` statements
+    fn stmt_for(
+        &self,
+        db_kind: &DbKind,
+        direction: &Direction,
+    ) -> Result<Option<&Cow<'static, str>>> {
+        let (stmt, variants) = match *direction {
+            Direction::Up => (self.up.as_ref(), &self.up_for),
+            Direction::Down => (self.down.as_ref(), &self.down_for),
+        };
+        variant_or(variants, db_kind, stmt, direction, &self.tag)
+    }
+
+    /// Toggle running statements in a transaction with the migration table update.
+    /// Defaults to `true`.
+    pub fn use_transaction(&mut self, transactional: bool) -> &mut Self {
+        self.transactional = transactional;
+        self
+    }
+
     /// Box this migration up so it can be stored with other migrations
     pub fn boxed(&self) -> Box<dyn Migratable> {
         Box::new(self.clone())
@@ -248,7 +604,7 @@ impl Migratable for EmbeddedMigration {
         _db_kind: DbKind,
         _config: &Config,
     ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        if let Some(ref _up) = self.up {
+        if let Some(_up) = self.stmt_for(&_db_kind, &Direction::Up)? {
             #[cfg(any(feature = "d-postgres", feature = "d-sqlite", feature = "d-mysql"))]
             match _db_kind {
                 DbKind::Sqlite => {
@@ -280,7 +636,7 @@ impl Migratable for EmbeddedMigration {
         db_kind: DbKind,
         config: &Config,
     ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        if let Some(ref down) = self.down {
+        if let Some(down) = self.stmt_for(&db_kind, &Direction::Down)? {
             match db_kind {
                 DbKind::Sqlite => {
                     let db_path = config.database_path()?;
@@ -304,6 +660,16 @@ impl Migratable for EmbeddedMigration {
         }
         Ok(())
     }
+    fn statements(
+        &self,
+        db_kind: &DbKind,
+        direction: &Direction,
+    ) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self.stmt_for(db_kind, direction)?.map(|s| s.to_string()))
+    }
+    fn is_transactional(&self) -> bool {
+        self.transactional
+    }
     fn tag(&self) -> String {
         self.tag.to_owned()
     }
@@ -312,6 +678,118 @@ impl Migratable for EmbeddedMigration {
     }
 }
 
+/// Define a repeatable migration, re-applied whenever its statements change.
+///
+/// Useful for views, functions and stored procedures that are easiest to maintain as
+/// a single `create or replace` script. Once all other migrations are applied, `Migrator`
+/// applies each repeatable migration that hasn't been applied yet or whose checksum differs
+/// from the one recorded when it was last applied. Repeatable migrations are applied in the
+/// order they're defined and are never reverted.
+///
+/// Statements can be read from a file at run-time with `RepeatableMigration::file`, or
+/// embedded with `RepeatableMigration::sql`. Repeatable migrations found under
+/// `migration_location` are `repeatable/<tag>.sql` files, or Flyway `R__<description>.sql` files.
+///
+/// ```rust,no_run
+/// # extern crate migrant_lib;
+/// # use migrant_lib::RepeatableMigration;
+/// # fn main() { run().unwrap(); }
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// RepeatableMigration::with_tag("active-users-view")
+///     .sql("create or replace view active_users as select * from users where active;");
+/// RepeatableMigration::with_tag("user-functions")
+///     .file("migrations/repeatable/user-functions.sql")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RepeatableMigration {
+    pub tag: String,
+    pub file: Option<PathBuf>,
+    pub sql: Option<Cow<'static, str>>,
+    pub(crate) transactional: bool,
+}
+impl RepeatableMigration {
+    /// Create a new `RepeatableMigration` with the given tag
+    pub fn with_tag(tag: &str) -> Self {
+        Self {
+            tag: tag.to_owned(),
+            file: None,
+            sql: None,
+            transactional: true,
+        }
+    }
+
+    /// Define the file containing the statements to apply.
+    ///
+    /// *Note:* The file must be present at run-time. File paths can be absolute
+    /// or relative. Relative file paths are relative to the directory from which
+    /// the program is run.
+    pub fn file<T: AsRef<Path>>(&mut self, file: T) -> Result<&mut Self> {
+        let path = file.as_ref();
+        FileMigration::check_path(path)?;
+        self.file = Some(path.to_owned());
+        Ok(self)
+    }
+
+    /// `&'static str` or `String` of statements to apply
+    pub fn sql<T: Into<Cow<'static, str>>>(&mut self, stmt: T) -> &mut Self {
+        self.sql = Some(stmt.into());
+        self
+    }
+
+    /// Toggle running statements in a transaction with the migration table update.
+    /// Defaults to `true`.
+    pub fn use_transaction(&mut self, transactional: bool) -> &mut Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// Box this migration up so it can be stored with other migrations
+    pub fn boxed(&self) -> Box<dyn Migratable> {
+        Box::new(self.clone())
+    }
+}
+
+impl Migratable for RepeatableMigration {
+    fn statements(
+        &self,
+        _: &DbKind,
+        direction: &Direction,
+    ) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+        if *direction == Direction::Down {
+            return Ok(None);
+        }
+        Ok(Some(match (&self.sql, &self.file) {
+            (Some(sql), _) => sql.to_string(),
+            (None, Some(path)) => fs::read_to_string(path)?,
+            (None, None) => {
+                return Err(Error::from(format_err!(
+                    ErrorKind::MigrationNotFound,
+                    "No statements defined for repeatable migration: {}",
+                    self.tag
+                ))
+                .into())
+            }
+        }))
+    }
+    fn is_transactional(&self) -> bool {
+        self.transactional
+    }
+    fn is_repeatable(&self) -> bool {
+        true
+    }
+    fn tag(&self) -> String {
+        self.tag.to_owned()
+    }
+    fn description(&self, _: &Direction) -> String {
+        match self.file {
+            Some(ref path) => format!("{:?}", path),
+            None => self.tag(),
+        }
+    }
+}
+
 /// No-op to use with `FnMigration`
 pub fn noop(_: ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
     Ok(())
@@ -353,8 +831,16 @@ pub struct FnMigration<T, U> {
 
 impl<T, U> FnMigration<T, U>
 where
-    T: 'static + Clone + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
-    U: 'static + Clone + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    T: 'static
+        + Clone
+        + Send
+        + Sync
+        + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    U: 'static
+        + Clone
+        + Send
+        + Sync
+        + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
 {
     /// Create a new `FnMigration` with the given tag
     #[cfg(not(any(feature = "d-postgres", feature = "d-sqlite", feature = "d-mysql")))]
@@ -396,14 +882,22 @@ where
 
 impl<T, U> Migratable for FnMigration<T, U>
 where
-    T: 'static + Clone + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
-    U: 'static + Clone + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    T: 'static
+        + Clone
+        + Send
+        + Sync
+        + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
+    U: 'static
+        + Clone
+        + Send
+        + Sync
+        + Fn(ConnConfig) -> std::result::Result<(), Box<dyn std::error::Error>>,
 {
     fn apply_up(
         &self,
         _: DbKind,
         config: &Config,
-    ) -> std::result::Result<(), Box<dyn (::std::error::Error)>> {
+    ) -> std::result::Result<(), Box<dyn ::std::error::Error>> {
         if let Some(ref up) = self.up {
             up(ConnConfig::new(config))?;
         } else {
@@ -416,7 +910,7 @@ where
         &self,
         _: DbKind,
         config: &Config,
-    ) -> std::result::Result<(), Box<dyn (::std::error::Error)>> {
+    ) -> std::result::Result<(), Box<dyn ::std::error::Error>> {
         if let Some(ref down) = self.down {
             down(ConnConfig::new(config))?;
         } else {
@@ -433,3 +927,384 @@ where
         self.tag()
     }
 }
+
+/// A function that can be run by a `TxFnMigration`, given a transaction of type `T`
+///
+/// Implemented for functions with one of the signatures:
+///
+/// - `fn(&rusqlite::Transaction) -> Result<(), Box<dyn std::error::Error>>` (`d-sqlite`)
+/// - `fn(&mut postgres::Transaction) -> Result<(), Box<dyn std::error::Error>>` (`d-postgres`)
+/// - `fn(&mut mysql::Transaction) -> Result<(), Box<dyn std::error::Error>>` (`d-mysql`)
+pub trait TxFn<T>: Send + Sync + 'static {
+    /// Open a transaction on `conn`, run this function and update the migration table
+    #[doc(hidden)]
+    fn run_tx(
+        &self,
+        conn: &mut dyn Driver,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>>;
+}
+
+type RunTx = Arc<
+    dyn Fn(
+            &mut dyn Driver,
+            &MigrationTable,
+            &MigrationRecord,
+            &Direction,
+        ) -> std::result::Result<(), Box<dyn std::error::Error>>
+        + Send
+        + Sync,
+>;
+
+/// Define a programmable migration that runs in a transaction
+///
+/// Unlike `FnMigration`s, `TxFnMigration` functions are given a transaction opened on
+/// the `Migrator`'s connection: a `&rusqlite::Transaction`, `&mut postgres::Transaction`
+/// or `&mut mysql::Transaction`. The migration table is updated in the same transaction,
+/// so changes made by a function are rolled back along with the migration if it fails.
+/// See `TxFn` for the supported signatures.
+///
+/// A direction without a function only updates the migration table.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate migrant_lib;
+/// # use migrant_lib::TxFnMigration;
+/// # fn main() { run().unwrap(); }
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// # #[cfg(feature = "d-sqlite")]
+/// # {
+/// fn add_data(tx: &rusqlite::Transaction) -> Result<(), Box<dyn std::error::Error>> {
+///     tx.execute("insert into users (name) values ('james')", [])?;
+///     Ok(())
+/// }
+///
+/// fn remove_data(tx: &rusqlite::Transaction) -> Result<(), Box<dyn std::error::Error>> {
+///     tx.execute("delete from users where name = 'james'", [])?;
+///     Ok(())
+/// }
+///
+/// TxFnMigration::with_tag("add-user-data")
+///     .up(add_data)
+///     .down(remove_data);
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TxFnMigration {
+    pub tag: String,
+    up: Option<RunTx>,
+    down: Option<RunTx>,
+}
+
+impl TxFnMigration {
+    /// Create a new `TxFnMigration` with the given tag
+    #[cfg(not(any(feature = "d-postgres", feature = "d-sqlite", feature = "d-mysql")))]
+    pub fn with_tag(_tag: &str) -> DatabaseFeatureRequired {
+        unimplemented!();
+    }
+
+    /// Create a new `TxFnMigration` with the given tag
+    #[cfg(any(feature = "d-postgres", feature = "d-sqlite", feature = "d-mysql"))]
+    pub fn with_tag(tag: &str) -> Self {
+        Self {
+            tag: tag.to_owned(),
+            up: None,
+            down: None,
+        }
+    }
+
+    fn run_tx<T, F: TxFn<T>>(f: F) -> RunTx {
+        Arc::new(move |conn, table, record, direction| f.run_tx(conn, table, record, direction))
+    }
+
+    /// Function to use for `up` migrations, see `TxFn` for the supported signatures
+    pub fn up<T, F: TxFn<T>>(&mut self, f_up: F) -> &mut Self {
+        self.up = Some(Self::run_tx(f_up));
+        self
+    }
+
+    /// Function to use for `down` migrations, see `TxFn` for the supported signatures
+    pub fn down<T, F: TxFn<T>>(&mut self, f_down: F) -> &mut Self {
+        self.down = Some(Self::run_tx(f_down));
+        self
+    }
+
+    /// Box this migration up so it can be stored with other migrations
+    pub fn boxed(&self) -> Box<dyn Migratable> {
+        Box::new(self.clone())
+    }
+
+    /// Async `Migrator`s can't open a transaction for `run`
+    #[cfg(feature = "async")]
+    fn unsupported_async(
+        &self,
+        run: &Option<RunTx>,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        match run {
+            Some(_) => Err(Error::from(format_err!(
+                ErrorKind::Migration,
+                "`TxFnMigration` `{}` can't be run by an async `Migrator`",
+                self.tag
+            ))
+            .into()),
+            None => {
+                print_flush!("(empty) ...");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for TxFnMigration {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TxFnMigration")
+            .field("tag", &self.tag)
+            .field("up", &self.up.is_some())
+            .field("down", &self.down.is_some())
+            .finish()
+    }
+}
+
+#[cfg_attr(feature = "async", async_trait::async_trait)]
+impl Migratable for TxFnMigration {
+    #[cfg(feature = "async")]
+    async fn apply_up_async(
+        &self,
+        _: DbKind,
+        _: &Config,
+    ) -> std::result::Result<(), Box<dyn ::std::error::Error>> {
+        self.unsupported_async(&self.up)
+    }
+
+    #[cfg(feature = "async")]
+    async fn apply_down_async(
+        &self,
+        _: DbKind,
+        _: &Config,
+    ) -> std::result::Result<(), Box<dyn ::std::error::Error>> {
+        self.unsupported_async(&self.down)
+    }
+
+    fn apply_on(
+        &self,
+        conn: &mut dyn Driver,
+        table: &MigrationTable,
+        record: &MigrationRecord,
+        direction: &Direction,
+    ) -> std::result::Result<bool, Box<dyn ::std::error::Error>> {
+        let run = match *direction {
+            Direction::Up => &self.up,
+            Direction::Down => &self.down,
+        };
+        match run {
+            Some(run) => {
+                run(conn, table, record, direction)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn tag(&self) -> String {
+        self.tag.to_owned()
+    }
+
+    fn description(&self, _: &Direction) -> String {
+        self.tag()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn up_only_sections() {
+        let (up, down) =
+            split_sections("-- leading comment\n\n-- migrate:up\ncreate table a(id int);\n")
+                .unwrap();
+        assert_eq!(Some("create table a(id int);\n".to_string()), up);
+        assert_eq!(None, down, "Assert missing down section is `None`");
+
+        let (up, down) =
+            split_sections("-- migrate:up\ncreate table a(id int);\n-- migrate:down\n\n").unwrap();
+        assert!(up.is_some());
+        assert_eq!(None, down, "Assert empty down section is `None`");
+    }
+
+    #[test]
+    fn repeated_section_markers() {
+        let contents =
+            "-- migrate:up\ncreate table a(id int);\n-- migrate:up\ncreate table b(id int);\n";
+        assert!(
+            split_sections(contents).is_err(),
+            "Assert duplicate up sections error"
+        );
+        let contents = "-- migrate:up\nselect 1;\n-- migrate:down\nselect 2;\n-- migrate:down\n";
+        assert!(
+            split_sections(contents).is_err(),
+            "Assert duplicate down sections error"
+        );
+    }
+
+    #[test]
+    fn section_marker_whitespace() {
+        let contents =
+            "  --\tmigrate:up   transaction:false  \ncreate index concurrently i on a(id);\n\
+                        \t-- migrate:down\ndrop index i;\n";
+        let (up, down) = split_sections(contents).unwrap();
+        let up = up.unwrap();
+        assert!(
+            has_no_transaction_marker(&up),
+            "Assert section options are parsed"
+        );
+        assert!(up.ends_with("create index concurrently i on a(id);\n"));
+        assert_eq!(Some("drop index i;\n".to_string()), down);
+
+        assert!(
+            parse_section_marker("-- migrate:up transaction:maybe").is_err(),
+            "Assert unknown section options error"
+        );
+        assert!(
+            parse_section_marker("--migrate:up").unwrap().is_none(),
+            "Assert markers require a space after `--`"
+        );
+    }
+
+    #[test]
+    fn missing_up_section() {
+        assert!(
+            split_sections("-- migrate:down\ndrop table a;\n").is_err(),
+            "Assert missing up section errors"
+        );
+        assert!(
+            split_sections("-- migrate:up\n\n-- migrate:down\ndrop table a;\n").is_err(),
+            "Assert empty up section errors"
+        );
+        assert!(
+            split_sections("create table a(id int);\n-- migrate:up\nselect 1;\n").is_err(),
+            "Assert statements before the first section error"
+        );
+    }
+
+    fn is_not_found<T: std::fmt::Debug>(res: Result<T>) -> bool {
+        matches!(res, Err(ref e) if matches!(*e.kind(), ErrorKind::MigrationNotFound(_)))
+    }
+
+    #[test]
+    fn variants() {
+        let mut variants = vec![];
+        set_variant(&mut variants, DbKind::Postgres, "pg");
+        set_variant(&mut variants, DbKind::MySql, "mysql");
+        set_variant(&mut variants, DbKind::Postgres, "pg-2");
+        assert_eq!(
+            vec![(DbKind::MySql, "mysql"), (DbKind::Postgres, "pg-2")],
+            variants,
+            "Assert setting a variant replaces the existing one"
+        );
+
+        let variant = |db_kind: DbKind, default: Option<&'static &'static str>| {
+            variant_or(&variants, &db_kind, default, &Direction::Up, "tag").map(|v| v.copied())
+        };
+        assert_eq!(
+            Some("pg-2"),
+            variant(DbKind::Postgres, Some(&"generic")).unwrap(),
+            "Assert variant for the database is used"
+        );
+        assert_eq!(
+            Some("generic"),
+            variant(DbKind::Sqlite, Some(&"generic")).unwrap(),
+            "Assert fallback to the default without a variant"
+        );
+        assert!(
+            is_not_found(variant(DbKind::Sqlite, None)),
+            "Assert missing variant without a default errors"
+        );
+        assert_eq!(
+            None,
+            variant_or::<&str>(&[], &DbKind::Sqlite, None, &Direction::Up, "tag").unwrap(),
+            "Assert no variants and no default is empty"
+        );
+    }
+
+    #[test]
+    fn file_migration_variants() {
+        let dir = crate::test::fixture_dir(
+            "file_migration_variants",
+            &[
+                ("up.sql", "generic up"),
+                ("up.postgres.sql", "postgres up"),
+                ("down.mysql.sql", "mysql down"),
+            ],
+        );
+        let mut mig = FileMigration::with_tag("variants");
+        mig.up(dir.join("up.sql"))
+            .unwrap()
+            .up_for(DbKind::Postgres, dir.join("up.postgres.sql"))
+            .unwrap()
+            .down_for(DbKind::MySql, dir.join("down.mysql.sql"))
+            .unwrap();
+        let statements =
+            |db_kind: DbKind, direction: Direction| mig.statements(&db_kind, &direction).unwrap();
+
+        assert_eq!(
+            Some("postgres up".to_string()),
+            statements(DbKind::Postgres, Direction::Up),
+            "Assert up variant used for its database"
+        );
+        assert_eq!(
+            Some("generic up".to_string()),
+            statements(DbKind::Sqlite, Direction::Up),
+            "Assert generic up file used by other databases"
+        );
+        assert_eq!(
+            Some("mysql down".to_string()),
+            statements(DbKind::MySql, Direction::Down),
+            "Assert down variant used for its database"
+        );
+        assert!(
+            is_not_found(mig.path_for(&DbKind::Sqlite, &Direction::Down)),
+            "Assert missing down variant without a generic down file errors"
+        );
+        assert!(
+            mig.up_for(DbKind::Sqlite, dir.join("missing.sql")).is_err(),
+            "Assert missing variant file errors"
+        );
+    }
+
+    #[cfg(feature = "d-sqlite")]
+    #[test]
+    fn embedded_migration_variants() {
+        let mut mig = EmbeddedMigration::with_tag("variants");
+        mig.up("generic up")
+            .up_for(DbKind::Postgres, "postgres up")
+            .down_for(DbKind::Sqlite, "sqlite down");
+        let statements =
+            |db_kind: DbKind, direction: Direction| mig.statements(&db_kind, &direction).unwrap();
+
+        assert_eq!(
+            Some("postgres up".to_string()),
+            statements(DbKind::Postgres, Direction::Up),
+            "Assert up variant used for its database"
+        );
+        assert_eq!(
+            Some("generic up".to_string()),
+            statements(DbKind::MySql, Direction::Up),
+            "Assert generic up statements used by other databases"
+        );
+        assert_eq!(
+            Some("sqlite down".to_string()),
+            statements(DbKind::Sqlite, Direction::Down),
+            "Assert down variant used for its database"
+        );
+        assert!(
+            is_not_found(mig.stmt_for(&DbKind::Postgres, &Direction::Down)),
+            "Assert missing down variant without generic down statements errors"
+        );
+    }
+}
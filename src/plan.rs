@@ -0,0 +1,56 @@
+/*!
+Migration plans
+
+*/
+use std::fmt;
+
+use crate::Direction;
+
+/// A single migration to be run as part of a `Plan`
+#[derive(Debug, Clone)]
+pub struct PlanStep {
+    pub tag: String,
+    pub direction: Direction,
+    pub description: String,
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.direction, self.description)
+    }
+}
+
+/// An ordered set of migrations to be run
+///
+/// Created by `Migrator::plan` and run by `Migrator::execute`.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub(crate) direction: Direction,
+    pub(crate) steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    /// The migrations to be run, in order
+    pub fn steps(&self) -> &[PlanStep] {
+        &self.steps
+    }
+
+    /// Number of migrations to be run
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Return `true` if there are no migrations to run
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
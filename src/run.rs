@@ -0,0 +1,257 @@
+/*!
+Running planned migrations
+
+The decisions shared by the blocking and async `Migrator`s. Each only runs the
+resulting `Op`s on its own connection type.
+
+*/
+use crate::drivers::{self, MigrationRecord};
+use crate::errors::*;
+use crate::migration;
+use crate::{Config, Direction, Migratable, Migrator, Plan, PlanStep};
+
+/// A database operation run while applying a migration
+pub(crate) enum Op {
+    /// Execute a batch of statements
+    Batch(String),
+    /// Run statements and insert or remove `record`, see `Driver::apply_migration`
+    Apply {
+        stmt: String,
+        record: MigrationRecord,
+        direction: Direction,
+        transactional: bool,
+    },
+    /// Insert a record into the migration table, taking `duration_ms` to apply
+    Insert(MigrationRecord, i64),
+    /// Remove a tag from the migration table
+    Remove(String),
+}
+
+/// Operations running the SQL statements `stmt` of `migration` in the given `direction`,
+/// along with the migration table update.
+///
+/// Statements are run in a transaction with the migration table update unless they opt out.
+/// A repeatable migration's record of the last time it was applied is replaced only
+/// once its statements have succeeded.
+pub(crate) fn statement_ops(
+    config: &Config,
+    migration: &dyn Migratable,
+    direction: &Direction,
+    stmt: String,
+) -> Result<Vec<Op>> {
+    let transactional =
+        migration.is_transactional() && !migration::has_no_transaction_marker(&stmt);
+    let checksum = match *direction {
+        Direction::Up => Some(migration::checksum(&stmt)),
+        Direction::Down => None,
+    };
+    let record = MigrationRecord::with_tag(&migration.tag())
+        .checksum(checksum)
+        .repeatable(migration.is_repeatable());
+    if !migration.is_repeatable() {
+        return Ok(vec![Op::Apply {
+            stmt,
+            record,
+            direction: direction.clone(),
+            transactional,
+        }]);
+    }
+    let replace = replace_statement(config, &record)?;
+    Ok(if transactional {
+        vec![Op::Apply {
+            stmt: format!("{}\n{}", replace, stmt),
+            record,
+            direction: direction.clone(),
+            transactional: true,
+        }]
+    } else {
+        vec![
+            Op::Batch(stmt),
+            Op::Apply {
+                stmt: replace,
+                record,
+                direction: direction.clone(),
+                transactional: true,
+            },
+        ]
+    })
+}
+
+/// Operation recording `migration` as applied or reverted in the given `direction`,
+/// without running it
+pub(crate) fn record_op(
+    config: &Config,
+    migration: &dyn Migratable,
+    direction: &Direction,
+    duration_ms: i64,
+) -> Result<Op> {
+    let tag = migration.tag();
+    if *direction == Direction::Down {
+        return Ok(Op::Remove(tag));
+    }
+    let checksum = migration
+        .checksum(&config.database_type())
+        .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?;
+    let record = MigrationRecord::with_tag(&tag)
+        .checksum(checksum)
+        .repeatable(migration.is_repeatable());
+    Ok(if migration.is_repeatable() {
+        // replace the record of the last time it was applied
+        Op::Apply {
+            stmt: replace_statement(config, &record)?,
+            record,
+            direction: Direction::Up,
+            transactional: true,
+        }
+    } else {
+        Op::Insert(record, duration_ms)
+    })
+}
+
+/// Statement removing the previous record of a repeatable migration
+fn replace_statement(config: &Config, record: &MigrationRecord) -> Result<String> {
+    Ok(drivers::render_migration_tag(
+        &config.database_type(),
+        &config.migration_table()?,
+        record,
+        &Direction::Down,
+    ))
+}
+
+impl Migrator {
+    /// Return an `ErrorKind::MigrationComplete` if there's nothing to run
+    pub(crate) fn check_not_empty(plan: &Plan) -> Result<()> {
+        if plan.is_empty() {
+            bail_fmt!(
+                ErrorKind::MigrationComplete,
+                "No un-applied `{}` migrations found",
+                plan.direction
+            )
+        }
+        Ok(())
+    }
+
+    /// Return the migration run by `step`
+    pub(crate) fn step_migration<'a>(
+        migrations: &'a [Box<dyn Migratable>],
+        step: &PlanStep,
+    ) -> Result<&'a dyn Migratable> {
+        migrations
+            .iter()
+            .find(|mig| mig.tag() == step.tag)
+            .map(|mig| mig.as_ref())
+            .ok_or_else(|| {
+                format_err!(ErrorKind::MigrationNotFound, "Tag not found: {}", step.tag).into()
+            })
+    }
+
+    /// Report that `step` is being run, returning `false` if its migration
+    /// shouldn't actually be run because `fake` is set
+    pub(crate) fn start_step(&self, step: &PlanStep) -> bool {
+        self.print(&format!(
+            "Applying[{}]: {}",
+            step.direction, step.description
+        ));
+        if self.fake {
+            self.println("  ✓ (fake)");
+        }
+        !self.fake
+    }
+
+    /// Report the result of running a step's migration. Returns an error if it failed,
+    /// unless `force` is set in which case `true` is returned so the step is recorded anyway
+    pub(crate) fn finish_step(&self, res: std::result::Result<(), String>) -> Result<bool> {
+        match res {
+            Ok(()) => {
+                self.println("  ✓");
+                Ok(false)
+            }
+            Err(e) => {
+                self.println("");
+                if !self.force {
+                    bail_fmt!(ErrorKind::Migration, "Migration was unsucessful...\n{}", e);
+                }
+                self.println(&format!(
+                    " ** Error ** (Continuing because `--force` flag was specified)\n ** {}",
+                    e
+                ));
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{RepeatableMigration, Settings};
+
+    fn sqlite_config() -> Config {
+        let settings = Settings::configure_sqlite()
+            .database_path(":memory:")
+            .unwrap()
+            .build()
+            .unwrap();
+        Config::with_settings(&settings)
+    }
+
+    /// `(statements, transactional)` run by each op, `None` for ops other than `Batch` and `Apply`
+    fn op_statements(ops: &[Op]) -> Vec<Option<(String, bool)>> {
+        ops.iter()
+            .map(|op| match op {
+                Op::Batch(stmt) => Some((stmt.clone(), false)),
+                Op::Apply {
+                    stmt,
+                    transactional,
+                    ..
+                } => Some((stmt.clone(), *transactional)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeatable_statement_ops() {
+        let config = sqlite_config();
+        let replace = "delete from __migrant_migrations where tag = 'view';";
+
+        let mig = RepeatableMigration::with_tag("view");
+        let ops = statement_ops(&config, &mig, &Direction::Up, "select 1;".into()).unwrap();
+        assert_eq!(
+            op_statements(&ops),
+            vec![Some((format!("{}\nselect 1;", replace), true))],
+            "Assert a transactional repeatable migration replaces its record in the same transaction"
+        );
+
+        let mut mig = RepeatableMigration::with_tag("view");
+        mig.use_transaction(false);
+        let ops = statement_ops(&config, &mig, &Direction::Up, "select 1;".into()).unwrap();
+        assert_eq!(
+            op_statements(&ops),
+            vec![
+                Some(("select 1;".to_string(), false)),
+                Some((replace.to_string(), true)),
+            ],
+            "Assert a non-transactional repeatable migration replaces its record after its statements"
+        );
+    }
+
+    #[test]
+    fn record_ops() {
+        let config = sqlite_config();
+        let mut mig = RepeatableMigration::with_tag("view");
+        mig.sql("select 1;");
+        match record_op(&config, &mig, &Direction::Up, 0).unwrap() {
+            Op::Apply { record, .. } => assert_eq!(
+                record.checksum,
+                Some(migration::checksum("select 1;")),
+                "Assert a repeatable migration is recorded with its checksum"
+            ),
+            _ => panic!("Assert a repeatable migration replaces its record"),
+        }
+        match record_op(&config, &mig, &Direction::Down, 0).unwrap() {
+            Op::Remove(tag) => assert_eq!(tag, "view"),
+            _ => panic!("Assert a reverted migration is removed from the migration table"),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+/*!
+Rendering planned migrations as SQL scripts
+
+*/
+use std::io::Write;
+
+use crate::drivers::{self, MigrationRecord};
+use crate::errors::*;
+use crate::migratable::Migratable;
+use crate::migration;
+use crate::plan::Plan;
+use crate::{Config, Direction, Migrator};
+
+/// Render all pending migrations in the given `direction` as a standalone SQL script.
+///
+/// For `Up` this is every un-applied migration, for `Down` every applied migration.
+/// Migrations are written in the order `Migrator` would run them, each wrapped in a
+/// transaction unless it opts out, along with the statements updating the migration
+/// table. Running the script leaves the database in the same state as applying with
+/// `Migrator`, so it can be handed to someone who runs reviewed scripts by hand.
+/// The migration table must already exist, see `Config::setup`.
+///
+/// Planning uses the applied migrations of `config`, so `Config::reload` first.
+/// Returns an empty script if nothing is pending.
+///
+/// Returns an `ErrorKind::Migration` if a pending migration isn't defined by SQL,
+/// like `FnMigration`s, since the script can't run it and would only record it as applied.
+///
+/// ```rust,no_run
+/// # extern crate migrant_lib;
+/// # use migrant_lib::{Config, Direction};
+/// # fn main() { run().unwrap(); }
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let config = Config::from_settings_file("Migrant.toml")?.reload()?;
+/// let script = migrant_lib::render_script(&config, Direction::Up)?;
+/// std::fs::write("pending.sql", script)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn render_script(config: &Config, direction: Direction) -> Result<String> {
+    let migrations = config.available_migrations()?;
+    let plan = Migrator::with_config(config)
+        .direction(direction)
+        .all(true)
+        .plan_with(config, &migrations)?;
+    check_sql_defined(config, &plan, &migrations)?;
+    let mut out = vec![];
+    write_plan(config, &plan, &migrations, false, &mut out)?;
+    String::from_utf8(out).map_err(|e| format_err!(ErrorKind::Migration, "{}", e).into())
+}
+
+/// Check that every migration of `plan` is defined by SQL statements
+fn check_sql_defined(
+    config: &Config,
+    plan: &Plan,
+    migrations: &[Box<dyn Migratable>],
+) -> Result<()> {
+    let db_kind = config.database_type();
+    for step in plan.steps() {
+        let mig = migrations.iter().find(|mig| mig.tag() == step.tag);
+        let stmt = match mig {
+            Some(mig) => mig.statements(&db_kind, &step.direction).map_err(|e| {
+                format_err!(ErrorKind::Migration, "Unable to load `{}`: {}", step.tag, e)
+            })?,
+            None => bail_fmt!(ErrorKind::MigrationNotFound, "Tag not found: {}", step.tag),
+        };
+        if stmt.is_none() {
+            bail_fmt!(
+                ErrorKind::Migration,
+                "`{}` is not defined by SQL statements and can't be rendered as a script",
+                step.tag
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Write the SQL run for each step of `plan` to `out`, including the migration table updates.
+///
+/// When `fake`, only the migration table updates are written. Migrations that aren't
+/// defined by SQL statements are noted in a comment.
+pub(crate) fn write_plan(
+    config: &Config,
+    plan: &Plan,
+    migrations: &[Box<dyn Migratable>],
+    fake: bool,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let db_kind = config.database_type();
+    let table = config.migration_table()?;
+    for step in plan.steps() {
+        let mig = migrations
+            .iter()
+            .find(|mig| mig.tag() == step.tag)
+            .ok_or_else(|| {
+                format_err!(ErrorKind::MigrationNotFound, "Tag not found: {}", step.tag)
+            })?;
+        let direction = &step.direction;
+        let stmt = if fake {
+            None
+        } else {
+            mig.statements(&db_kind, direction).map_err(|e| {
+                format_err!(ErrorKind::Migration, "Unable to load `{}`: {}", step.tag, e)
+            })?
+        };
+        let checksum = match (direction, &stmt) {
+            (Direction::Up, Some(stmt)) => Some(migration::checksum(stmt)),
+            (Direction::Up, None) => mig
+                .checksum(&db_kind)
+                .map_err(|e| format_err!(ErrorKind::Migration, "{}", e))?,
+            (Direction::Down, _) => None,
+        };
+        let record = MigrationRecord::with_tag(&step.tag)
+            .checksum(checksum)
+            .repeatable(mig.is_repeatable());
+        let transactional = match stmt {
+            Some(ref stmt) => mig.is_transactional() && !migration::has_no_transaction_marker(stmt),
+            None => false,
+        };
+
+        // a repeatable's previous record is replaced along with the statements, as `Migrator` does
+        let replace = if mig.is_repeatable() {
+            Some(drivers::render_migration_tag(
+                &db_kind,
+                &table,
+                &record,
+                &Direction::Down,
+            ))
+        } else {
+            None
+        };
+
+        writeln!(out, "-- [{}] {}", direction, step.description)?;
+        if transactional {
+            writeln!(out, "{}", drivers::render_begin(&db_kind))?;
+            if let Some(ref replace) = replace {
+                writeln!(out, "{}", replace)?;
+            }
+        }
+        match stmt {
+            Some(ref stmt) => writeln!(out, "{}", stmt.trim_end())?,
+            None if !fake => writeln!(
+                out,
+                "-- `{}` is not defined by SQL statements and can't be shown",
+                step.tag
+            )?,
+            None => (),
+        }
+        if let Some(replace) = replace.filter(|_| !transactional) {
+            writeln!(out, "{}", replace)?;
+        }
+        writeln!(
+            out,
+            "{}",
+            drivers::render_migration_tag(&db_kind, &table, &record, direction)
+        )?;
+        if transactional {
+            writeln!(out, "commit;")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{RepeatableMigration, Settings};
+
+    /// A migration that isn't defined by SQL statements
+    #[derive(Clone, Debug)]
+    struct NotSql;
+    impl Migratable for NotSql {
+        fn tag(&self) -> String {
+            "not-sql".into()
+        }
+    }
+
+    fn config_with(migrations: Vec<Box<dyn Migratable>>) -> Config {
+        let settings = Settings::configure_sqlite()
+            .database_path("/tmp/migrant_script_test.db")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut config = Config::with_settings(&settings);
+        config.use_migrations(migrations).unwrap();
+        config
+    }
+
+    /// Lines of `script` that aren't blank or comments
+    fn statement_lines(script: &str) -> Vec<&str> {
+        script
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("--"))
+            .collect()
+    }
+
+    #[test]
+    fn render_sql_migrations() {
+        let mut config = config_with(vec![RepeatableMigration::with_tag("view")
+            .sql("create view v as select 1;")
+            .boxed()]);
+        // as loaded by `Config::reload` before the repeatable is first applied
+        config.changed_repeatable = vec!["view".into()];
+        let script = render_script(&config, Direction::Up).unwrap();
+        let lines = statement_lines(&script);
+        assert_eq!(5, lines.len(), "Assert script lines: {:?}", lines);
+        assert_eq!("begin;", lines[0], "Assert transaction begins first");
+        assert!(
+            lines[1].starts_with("delete from __migrant_migrations where tag = 'view'"),
+            "Assert previous record is deleted inside the transaction"
+        );
+        assert_eq!(
+            "create view v as select 1;", lines[2],
+            "Assert migration statements are rendered"
+        );
+        assert!(
+            lines[3].starts_with("insert into __migrant_migrations"),
+            "Assert migration table update is rendered"
+        );
+        assert_eq!("commit;", lines[4], "Assert transaction is committed last");
+
+        // without a transaction the record is only replaced once the statements succeed
+        config
+            .use_migrations(vec![RepeatableMigration::with_tag("view")
+                .sql("create view v as select 1;")
+                .use_transaction(false)
+                .boxed()])
+            .unwrap();
+        let script = render_script(&config, Direction::Up).unwrap();
+        let lines = statement_lines(&script);
+        assert_eq!(3, lines.len(), "Assert script lines: {:?}", lines);
+        assert_eq!("create view v as select 1;", lines[0]);
+        assert!(
+            lines[1].starts_with("delete from"),
+            "Assert delete follows statements"
+        );
+        assert!(
+            lines[2].starts_with("insert into"),
+            "Assert insert follows delete"
+        );
+    }
+
+    #[test]
+    fn render_rejects_non_sql_migrations() {
+        let config = config_with(vec![Box::new(NotSql)]);
+        let err = render_script(&config, Direction::Up).unwrap_err();
+        match *err.kind() {
+            ErrorKind::Migration(ref msg) => assert!(msg.contains("not-sql"), "{}", msg),
+            ref kind => panic!("Expected a migration error, found: {:?}", kind),
+        }
+    }
+}